@@ -0,0 +1,45 @@
+use alloy::transports::icp::RpcService;
+use evm_rpc_canister_types::RpcServices;
+
+use crate::state::{mutate_state, AuditLogEntry, InvalidStateError, ScrapingBackend, State};
+
+/// Applies a configuration change requested by a controller. If `f` succeeds, the
+/// change is recorded in the audit log, otherwise the state is left untouched.
+pub fn apply_config_change<F>(change: String, f: F) -> Result<(), String>
+where
+    F: FnOnce(&mut State) -> Result<(), InvalidStateError>,
+{
+    mutate_state(|s| {
+        f(s).map_err(|e| format!("{:?}", e))?;
+        s.record_audit_log_entry(AuditLogEntry {
+            timestamp: ic_cdk::api::time(),
+            caller: ic_cdk::caller(),
+            change,
+        });
+        Ok(())
+    })
+}
+
+/// Describes `rpc_service` for the audit log. The URL and headers of a custom API are
+/// left out, as they may contain API keys.
+pub fn describe_rpc_service(rpc_service: &RpcService) -> String {
+    match rpc_service {
+        RpcService::Custom(_) => "a custom RPC API".to_string(),
+        rpc_service => format!("{:?}", rpc_service),
+    }
+}
+
+/// Describes `scraping_backend` for the audit log, leaving out custom APIs like
+/// `describe_rpc_service`.
+pub fn describe_scraping_backend(scraping_backend: &ScrapingBackend) -> String {
+    match scraping_backend {
+        ScrapingBackend::Provider => "Provider".to_string(),
+        ScrapingBackend::EvmRpc(config) => {
+            let rpc_services = match &config.rpc_services {
+                RpcServices::Custom { .. } => "custom RPC APIs".to_string(),
+                rpc_services => format!("{:?}", rpc_services),
+            };
+            format!("EvmRpc with {} and {:?}", rpc_services, config.consensus)
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::time::Duration;
+
+use alloy::primitives::{hex, Address, Signature, U256};
+use alloy::signers::Signer;
+use alloy::sol;
+use alloy::sol_types::{eip712_domain, SolStruct};
+use candid::{CandidType, Deserialize, Nat};
+use serde::Serialize;
+
+use crate::endpoints::to_nat;
+use crate::state::read_state;
+
+sol! {
+    /// The EIP-712 typed struct that the canister signs for an attested result, see
+    /// `Coprocessor.submitAttestedResult`.
+    struct JobResult {
+        uint256 jobId;
+        address requester;
+        string result;
+        uint256 deadline;
+        uint256 chainId;
+        address contractAddress;
+    }
+}
+
+/// The name and version of the EIP-712 domain of the coprocessor contract.
+const DOMAIN_NAME: &str = "Coprocessor";
+const DOMAIN_VERSION: &str = "1";
+
+/// How long an attestation can be relayed, see `renew`.
+pub const ATTESTATION_VALIDITY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Identifies an attestation by the chain and the job id that the result is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AttestationKey {
+    pub chain_id: u64,
+    pub job_id: U256,
+}
+
+/// The result of a job signed by the canister, which anyone can relay to the
+/// coprocessor contract on `chain_id`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attestation {
+    pub chain_id: u64,
+    pub coprocessor_evm_address: Address,
+    pub job_id: U256,
+    /// The account that created the job. The contract only accepts the result for
+    /// the job with this requester, so that the attestation cannot be used for
+    /// another job that got the same id after a reorg.
+    #[serde(default)]
+    pub requester: Address,
+    pub result: String,
+    /// The time until which the attestation can be relayed, in seconds since the
+    /// epoch.
+    #[serde(default)]
+    pub deadline: u64,
+    /// The 65 bytes `r || s || v` of the signature of the EIP-712 hash of the
+    /// `JobResult`, with a low `s`.
+    pub signature: Vec<u8>,
+}
+
+impl Attestation {
+    pub fn key(&self) -> AttestationKey {
+        AttestationKey {
+            chain_id: self.chain_id,
+            job_id: self.job_id,
+        }
+    }
+}
+
+/// The Candid representation of an `Attestation`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AttestationInfo {
+    pub chain_id: u64,
+    pub coprocessor_evm_address: String,
+    pub job_id: Nat,
+    pub requester: String,
+    pub result: String,
+    pub deadline: u64,
+    /// The hex encoded signature, as expected by `submitAttestedResult`.
+    pub signature: String,
+}
+
+impl From<Attestation> for AttestationInfo {
+    fn from(attestation: Attestation) -> Self {
+        Self {
+            chain_id: attestation.chain_id,
+            coprocessor_evm_address: attestation.coprocessor_evm_address.to_string(),
+            job_id: to_nat(attestation.job_id),
+            requester: attestation.requester.to_string(),
+            result: attestation.result,
+            deadline: attestation.deadline,
+            signature: hex::encode_prefixed(attestation.signature),
+        }
+    }
+}
+
+/// Signs the `result` of the job `job_id` created by `requester` for the coprocessor
+/// contract on `chain_id` with the canister's threshold ECDSA key. The attestation
+/// expires after `ATTESTATION_VALIDITY`.
+pub async fn attest(
+    chain_id: u64,
+    job_id: U256,
+    requester: Address,
+    result: String,
+) -> Result<Attestation, String> {
+    let signer = read_state(|s| s.signer.clone()).ok_or("the signer is not initialized yet")?;
+    let coprocessor_evm_address = read_state(|s| s.chain(chain_id).coprocessor_evm_address);
+    let deadline =
+        Duration::from_nanos(ic_cdk::api::time()).as_secs() + ATTESTATION_VALIDITY.as_secs();
+    let domain = eip712_domain! {
+        name: DOMAIN_NAME,
+        version: DOMAIN_VERSION,
+        chain_id: chain_id,
+        verifying_contract: coprocessor_evm_address,
+    };
+    let job_result = JobResult {
+        jobId: job_id,
+        requester,
+        result: result.clone(),
+        deadline: U256::from(deadline),
+        chainId: U256::from(chain_id),
+        contractAddress: coprocessor_evm_address,
+    };
+    let signature = signer
+        .sign_hash(&job_result.eip712_signing_hash(&domain))
+        .await
+        .map_err(|e| e.to_string())?;
+    // the contract rejects malleable signatures with a high `s`
+    let signature = signature.normalize_s().unwrap_or(signature);
+    Ok(Attestation {
+        chain_id,
+        coprocessor_evm_address,
+        job_id,
+        requester,
+        result,
+        deadline,
+        signature: signature.as_bytes().to_vec(),
+    })
+}
+
+/// Signs an expired attestation again with a new deadline.
+pub async fn renew(attestation: Attestation) -> Result<Attestation, String> {
+    let now = Duration::from_nanos(ic_cdk::api::time()).as_secs();
+    if attestation.deadline > now {
+        return Err(format!(
+            "the attestation is valid until {}",
+            attestation.deadline
+        ));
+    }
+    attest(
+        attestation.chain_id,
+        attestation.job_id,
+        attestation.requester,
+        attestation.result,
+    )
+    .await
+}
+
+/// The message that the requester of a job signs to renew its expired attestation,
+/// see `verify_requester`. It names the expired deadline, so that the signature cannot
+/// be used for any later renewal.
+pub fn renewal_message(attestation: &Attestation) -> String {
+    format!(
+        "Renew the attestation of job {} on chain {} that expired at {}",
+        attestation.job_id, attestation.chain_id, attestation.deadline
+    )
+}
+
+/// Checks that `signature`, a hex encoded EIP-191 signature of the `renewal_message`,
+/// was made by the requester of the job.
+pub fn verify_requester(attestation: &Attestation, signature: &str) -> Result<(), String> {
+    let signature = hex::decode(signature)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| Signature::try_from(bytes.as_slice()).map_err(|e| e.to_string()))
+        .map_err(|e| format!("invalid signature: {}", e))?;
+    let signer = signature
+        .recover_address_from_msg(renewal_message(attestation))
+        .map_err(|e| format!("invalid signature: {}", e))?;
+    if signer != attestation.requester {
+        return Err(format!(
+            "the signature is by {} instead of the requester {}",
+            signer, attestation.requester
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use std::future::{poll_fn, Future};
+use std::ops::AddAssign;
+use std::pin::pin;
+
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+use crate::state::{mutate_state, read_state, AuditLogEntry};
+
+/// The fee of a threshold ECDSA signature with the production key `key_1`. All other
+/// keys, such as `test_key_1` and the local `dfx_test_key`, are charged like the test
+/// key.
+const KEY_1_SIGNATURE_FEE: u128 = 26_153_846_153;
+const TEST_KEY_SIGNATURE_FEE: u128 = 10_000_000_000;
+
+/// The cycles spent on a job, or by the canister as a whole, by what they were spent
+/// on.
+#[derive(CandidType, Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CyclesSpent {
+    /// Fetching the logs. A job is charged its share of the request that returned
+    /// its log.
+    pub scraping: u128,
+    /// Threshold ECDSA signatures of result transactions and attestations.
+    pub signing: u128,
+    /// All other calls to the RPC providers, e.g. to reserve nonces, send
+    /// transactions and poll receipts.
+    pub rpc: u128,
+}
+
+impl CyclesSpent {
+    pub fn scraping(cycles: u128) -> Self {
+        Self {
+            scraping: cycles,
+            ..Default::default()
+        }
+    }
+
+    pub fn rpc(cycles: u128) -> Self {
+        Self {
+            rpc: cycles,
+            ..Default::default()
+        }
+    }
+
+    /// Splits the cycles spent on sending a transaction into the fee of its signature,
+    /// if it was signed, and the RPC calls.
+    pub fn transaction(cycles: u128, signed: bool) -> Self {
+        let signing = if signed {
+            signature_fee().min(cycles)
+        } else {
+            0
+        };
+        Self {
+            scraping: 0,
+            signing,
+            rpc: cycles - signing,
+        }
+    }
+
+    /// Returns the share of one of `n` jobs that the cycles were spent on together.
+    pub fn share(&self, n: usize) -> Self {
+        let n = n.max(1) as u128;
+        Self {
+            scraping: self.scraping / n,
+            signing: self.signing / n,
+            rpc: self.rpc / n,
+        }
+    }
+
+    pub fn total(&self) -> u128 {
+        self.scraping + self.signing + self.rpc
+    }
+}
+
+impl AddAssign for CyclesSpent {
+    fn add_assign(&mut self, other: Self) {
+        self.scraping += other.scraping;
+        self.signing += other.signing;
+        self.rpc += other.rpc;
+    }
+}
+
+/// The fee charged for one signature with the canister's threshold ECDSA key.
+fn signature_fee() -> u128 {
+    read_state(|s| match s.ecdsa_key_id.name.as_str() {
+        "key_1" => KEY_1_SIGNATURE_FEE,
+        _ => TEST_KEY_SIGNATURE_FEE,
+    })
+}
+
+/// Awaits `f` and returns its output together with the cycles it spent, i.e. the
+/// cycles attached to the calls that `f` makes minus the cycles refunded with their
+/// responses. The balance is only compared while `f` itself runs, so the calls of
+/// other jobs and top-ups in the meantime are not counted.
+pub async fn measure<T>(f: impl Future<Output = T>) -> (T, u128) {
+    let mut f = pin!(f);
+    let mut spent: u128 = 0;
+    let mut is_first_poll = true;
+    let output = poll_fn(|cx| {
+        // every poll but the first runs in the callback of a call that `f` awaited,
+        // whose unused cycles were refunded before the callback
+        if !is_first_poll {
+            spent = spent.saturating_sub(ic_cdk::api::call::msg_cycles_refunded128());
+        }
+        is_first_poll = false;
+        let balance_before = ic_cdk::api::canister_balance128();
+        let poll = f.as_mut().poll(cx);
+        spent += balance_before.saturating_sub(ic_cdk::api::canister_balance128());
+        poll
+    })
+    .await;
+    (output, spent)
+}
+
+/// Pauses processing and raises an alert if the cycles balance fell below the
+/// configured `min_cycles_balance`, before the canister is drained and frozen.
+/// Returns whether the tasks that spend cycles, i.e. processing, scraping, the
+/// transaction monitor and the gas balance checks, may go on.
+pub fn check_cycles_balance() -> bool {
+    let balance = ic_cdk::api::canister_balance128();
+    mutate_state(|s| {
+        let Some(min_cycles_balance) = s.min_cycles_balance else {
+            return true;
+        };
+        if balance >= min_cycles_balance {
+            return true;
+        }
+        if !s.processing_paused {
+            let now = ic_cdk::api::time();
+            s.processing_paused = true;
+            s.record_audit_log_entry(AuditLogEntry {
+                timestamp: now,
+                caller: ic_cdk::id(),
+                change: format!(
+                    "pause processing: the cycles balance of {} is below the minimum of {}",
+                    balance, min_cycles_balance
+                ),
+            });
+            s.record_alert(
+                format!(
+                    "the cycles balance of {} is below the minimum of {}, processing is paused until a controller resumes it",
+                    balance, min_cycles_balance
+                ),
+                now,
+            );
+        }
+        false
+    })
+}
+
+/// The Candid representation of the canister's cycles usage.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CyclesUsage {
+    pub balance: u128,
+    pub min_cycles_balance: Option<u128>,
+    /// The cycles spent since the canister was installed.
+    pub spent: CyclesSpent,
+}
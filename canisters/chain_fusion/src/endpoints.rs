@@ -0,0 +1,230 @@
+use crate::cycles::CyclesSpent;
+use crate::job::decode_job_id;
+use crate::state::{self, Job, ProcessedJob, State};
+use crate::storage;
+use alloy::primitives::{B256, U256};
+use candid::{CandidType, Deserialize, Nat};
+use std::str::FromStr;
+
+/// The Candid representation of a `state::LogSource`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LogSource {
+    pub transaction_hash: String,
+    pub log_index: u64,
+}
+
+impl From<&state::LogSource> for LogSource {
+    fn from(source: &state::LogSource) -> Self {
+        Self {
+            transaction_hash: source.transaction_hash.to_string(),
+            log_index: source.log_index,
+        }
+    }
+}
+
+impl TryFrom<LogSource> for state::LogSource {
+    type Error = String;
+
+    fn try_from(source: LogSource) -> Result<Self, Self::Error> {
+        let transaction_hash = B256::from_str(&source.transaction_hash)
+            .map_err(|e| format!("invalid transaction hash: {}", e))?;
+        Ok(Self {
+            transaction_hash,
+            log_index: source.log_index,
+        })
+    }
+}
+
+/// The maximum number of jobs returned by a single paginated query.
+pub const MAX_JOBS_PER_PAGE: usize = 100;
+
+/// The Candid representation of a `state::JobStatus`, extended by the collections a
+/// job can be in outside of the queue and the processed logs.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum JobStatus {
+    Pending,
+    Computing,
+    Computed,
+    Submitted {
+        tx_hash: String,
+    },
+    Confirmed {
+        tx_hash: String,
+    },
+    ExecutionReverted {
+        tx_hash: String,
+        reason: String,
+    },
+    Failed {
+        reason: String,
+    },
+    Compensated,
+    Unhandled,
+    /// The job paid less than the payment policy requires, both in wei.
+    Rejected {
+        paid: Nat,
+        price: Nat,
+    },
+    /// The result was signed instead of sent, see `get_attestation`.
+    Attested,
+    /// The job ran out of attempts, see `get_dead_letter_jobs`.
+    DeadLetter,
+    /// The job's block was reorged out of the chain.
+    Reverted,
+}
+
+impl From<&state::JobStatus> for JobStatus {
+    fn from(status: &state::JobStatus) -> Self {
+        match status {
+            state::JobStatus::Pending => Self::Pending,
+            state::JobStatus::Computing => Self::Computing,
+            state::JobStatus::Computed => Self::Computed,
+            state::JobStatus::Submitted { tx_hash } => Self::Submitted {
+                tx_hash: tx_hash.to_string(),
+            },
+            state::JobStatus::Confirmed { tx_hash } => Self::Confirmed {
+                tx_hash: tx_hash.to_string(),
+            },
+            state::JobStatus::ExecutionReverted { tx_hash, reason } => Self::ExecutionReverted {
+                tx_hash: tx_hash.to_string(),
+                reason: reason.clone(),
+            },
+            state::JobStatus::Failed { reason } => Self::Failed {
+                reason: reason.clone(),
+            },
+            state::JobStatus::Compensated => Self::Compensated,
+            state::JobStatus::Unhandled => Self::Unhandled,
+            state::JobStatus::Rejected { paid, price } => Self::Rejected {
+                paid: to_nat(*paid),
+                price: to_nat(*price),
+            },
+            state::JobStatus::Attested => Self::Attested,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JobInfo {
+    pub log_source: LogSource,
+    /// The chain that emitted the job's log.
+    pub chain_id: u64,
+    /// The chain that the job's result is posted to, once it is known.
+    pub result_chain_id: Option<u64>,
+    pub block_number: Option<u64>,
+    pub job_id: Option<Nat>,
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// The hash of the transaction that submitted the job's result.
+    pub result_tx_hash: Option<String>,
+    /// The gas used by the mined result transaction.
+    pub gas_used: Option<Nat>,
+    /// The cycles spent on the job so far.
+    pub cycles: CyclesSpent,
+}
+
+impl JobInfo {
+    pub fn new(source: &state::LogSource, job: &Job, s: &State) -> Self {
+        let filter_events = s
+            .chains
+            .get(&job.chain_id)
+            .map(|chain| chain.filter_events.as_slice())
+            .unwrap_or_default();
+        Self {
+            log_source: source.into(),
+            chain_id: job.chain_id,
+            result_chain_id: job.result_chain_id,
+            block_number: job.log.block_number,
+            job_id: job
+                .job_id
+                .or_else(|| decode_job_id(filter_events, &job.log))
+                .map(to_nat),
+            status: (&job.status).into(),
+            attempts: job.attempts,
+            last_error: job.last_error.clone(),
+            result_tx_hash: result_tx_hash(&job.status),
+            gas_used: job.gas_used.map(Nat::from),
+            cycles: job.cycles,
+        }
+    }
+
+    pub fn from_processed(source: &state::LogSource, job: &ProcessedJob) -> Self {
+        Self {
+            log_source: source.into(),
+            chain_id: job.chain_id,
+            result_chain_id: job.result_chain_id,
+            block_number: job.block_number,
+            job_id: job.job_id.map(to_nat),
+            status: (&job.status).into(),
+            attempts: job.attempts,
+            last_error: job.last_error.clone(),
+            result_tx_hash: result_tx_hash(&job.status),
+            gas_used: job.gas_used.map(Nat::from),
+            cycles: job.cycles,
+        }
+    }
+
+    pub fn with_status(mut self, status: JobStatus) -> Self {
+        self.status = status;
+        self
+    }
+}
+
+pub fn to_nat(value: U256) -> Nat {
+    Nat::from_str(&value.to_string()).expect("BUG: a U256 is always a valid nat")
+}
+
+pub fn from_nat(value: &Nat) -> Result<U256, String> {
+    U256::from_str(&value.0.to_string()).map_err(|e| format!("invalid uint256 {}: {}", value, e))
+}
+
+fn result_tx_hash(status: &state::JobStatus) -> Option<String> {
+    match status {
+        state::JobStatus::Submitted { tx_hash }
+        | state::JobStatus::Confirmed { tx_hash }
+        | state::JobStatus::ExecutionReverted { tx_hash, .. } => Some(tx_hash.to_string()),
+        _ => None,
+    }
+}
+
+/// Returns the page of `jobs` starting at `offset` with at most `limit` entries.
+pub fn paginate<'a>(
+    jobs: impl Iterator<Item = (&'a state::LogSource, &'a Job)>,
+    s: &State,
+    offset: u64,
+    limit: u64,
+) -> Vec<JobInfo> {
+    jobs.skip(offset as usize)
+        .take((limit as usize).min(MAX_JOBS_PER_PAGE))
+        .map(|(source, job)| JobInfo::new(source, job, s))
+        .collect()
+}
+
+/// Looks up the job for `source` in all collections of the state.
+pub fn get_job(s: &State, source: &state::LogSource) -> Option<JobInfo> {
+    if let Some(job) = s.logs_to_process.get(source) {
+        return Some(JobInfo::new(source, job, s));
+    }
+    if let Some(job) = storage::get_processed_job(source) {
+        return Some(JobInfo::from_processed(source, &job));
+    }
+    if let Some(job) = s.dead_letter_jobs.get(source) {
+        return Some(JobInfo::new(source, job, s).with_status(JobStatus::DeadLetter));
+    }
+    if let Some(reverted) = s.reverted_logs.get(source) {
+        return Some(JobInfo {
+            log_source: source.into(),
+            chain_id: reverted.chain_id,
+            result_chain_id: None,
+            block_number: reverted.block_number,
+            job_id: None,
+            status: JobStatus::Reverted,
+            attempts: 0,
+            last_error: None,
+            result_tx_hash: None,
+            gas_used: None,
+            cycles: Default::default(),
+        });
+    }
+    None
+}
@@ -0,0 +1,143 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use alloy::primitives::U256;
+use alloy::providers::{Provider, ProviderBuilder};
+use alloy::transports::icp::IcpConfig;
+use candid::{CandidType, Deserialize, Nat};
+use ic_cdk::println;
+use serde::Serialize;
+
+use crate::cycles::{check_cycles_balance, measure, CyclesSpent};
+use crate::endpoints::to_nat;
+use crate::guard::TimerGuard;
+use crate::job::start_jobs;
+use crate::state::{mutate_state, read_state, ChainState, JobStatus, ResultSink, State, TaskType};
+
+/// How often the balance of the canister's EVM address is checked on each result
+/// chain.
+pub const CHECK_GAS_BALANCES_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// The gas assumed for a `callback` transaction until one has been mined on the chain.
+const DEFAULT_CALLBACK_GAS: u128 = 100_000;
+
+/// The last known balance of the canister's EVM address on a chain, compared to what
+/// the next result transaction will cost.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GasBalance {
+    /// The balance in wei.
+    pub balance: U256,
+    /// The estimated cost of the next `callback` transaction in wei: the gas used by
+    /// the last mined one at the current max fee per gas.
+    pub callback_cost: U256,
+    /// When the balance was fetched, in nanoseconds since the epoch.
+    pub checked_at: u64,
+}
+
+impl GasBalance {
+    /// Whether the balance covers the next `callbacks` result transactions.
+    pub fn covers(&self, callbacks: u64) -> bool {
+        self.balance >= self.callback_cost.saturating_mul(U256::from(callbacks))
+    }
+}
+
+/// The Candid representation of the gas balance of the canister's EVM address on one
+/// chain.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GasBalanceStatus {
+    pub chain_id: u64,
+    /// The balance in wei, `None` until it has been checked.
+    pub balance: Option<Nat>,
+    /// The estimated cost of the next `callback` transaction in wei.
+    pub callback_cost: Option<Nat>,
+    pub checked_at: Option<u64>,
+    /// Whether jobs whose results are posted to this chain are started. `true` while
+    /// the balance is unknown.
+    pub sufficient: bool,
+    /// The queued jobs whose results are posted to this chain and that wait for
+    /// their first submission.
+    pub waiting_jobs: u64,
+}
+
+impl GasBalanceStatus {
+    pub fn new(chain: &ChainState, s: &State) -> Self {
+        let gas_balance = chain.gas_balance.as_ref();
+        Self {
+            chain_id: chain.chain_id,
+            balance: gas_balance.map(|gas| to_nat(gas.balance)),
+            callback_cost: gas_balance.map(|gas| to_nat(gas.callback_cost)),
+            checked_at: gas_balance.map(|gas| gas.checked_at),
+            sufficient: chain.has_gas_for_callbacks(1),
+            waiting_jobs: s
+                .logs_to_process
+                .values()
+                .filter(|job| {
+                    matches!(
+                        job.status,
+                        JobStatus::Pending | JobStatus::Computing | JobStatus::Computed
+                    ) && s.job_result_chain_id(job) == Some(chain.chain_id)
+                })
+                .count() as u64,
+        }
+    }
+}
+
+/// Checks the balance of the canister's EVM address on every chain that results are
+/// sent to with result transactions. Jobs are only started while the balance covers
+/// the next result transaction, so the jobs that were held back are started once it
+/// is topped up.
+pub async fn check_gas_balances() {
+    let _guard = match TimerGuard::new(TaskType::CheckGasBalances) {
+        Ok(guard) => guard,
+        Err(_) => return,
+    };
+    if !check_cycles_balance() {
+        return;
+    }
+
+    let chain_ids: BTreeSet<u64> = read_state(|s| {
+        s.chains
+            .values()
+            .map(ChainState::result_chain_id)
+            .filter(|chain_id| s.chain(*chain_id).result_sink == ResultSink::Transaction)
+            .collect()
+    });
+    for chain_id in chain_ids {
+        let (gas_balance, cycles) = measure(fetch_gas_balance(chain_id)).await;
+        mutate_state(|s| s.cycles_spent += CyclesSpent::rpc(cycles));
+        match gas_balance {
+            Ok(gas_balance) => mutate_state(|s| s.record_gas_balance(chain_id, gas_balance)),
+            Err(e) => println!(
+                "failed to check the gas balance on chain {}: {}",
+                chain_id, e
+            ),
+        }
+    }
+    start_jobs();
+}
+
+async fn fetch_gas_balance(chain_id: u64) -> Result<GasBalance, String> {
+    let evm_address = read_state(|s| s.canister_evm_address)
+        .ok_or("the canister's EVM address is not initialized yet")?;
+    let (rpc_service, callback_gas) = read_state(|s| {
+        let chain = s.chain(chain_id);
+        (
+            chain.rpc_service.clone(),
+            chain.last_callback_gas_used.unwrap_or(DEFAULT_CALLBACK_GAS),
+        )
+    });
+    let provider = ProviderBuilder::new().on_icp(IcpConfig::new(rpc_service));
+    let balance = provider
+        .get_balance(evm_address)
+        .latest()
+        .await
+        .map_err(|e| e.to_string())?;
+    let fees = provider
+        .estimate_eip1559_fees(None)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(GasBalance {
+        balance,
+        callback_cost: U256::from(callback_gas).saturating_mul(U256::from(fees.max_fee_per_gas)),
+        checked_at: ic_cdk::api::time(),
+    })
+}
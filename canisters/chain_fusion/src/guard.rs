@@ -1,4 +1,51 @@
-use crate::state::{mutate_state, TaskType};
+use std::time::Duration;
+
+use candid::{CandidType, Deserialize};
+use ic_cdk::println;
+
+use crate::attestation::AttestationKey;
+use crate::gas::CHECK_GAS_BALANCES_INTERVAL;
+use crate::job::MONITOR_TRANSACTIONS_INTERVAL;
+use crate::state::{mutate_state, LogSource, State, TaskType};
+use crate::SCRAPING_LOGS_INTERVAL;
+
+/// How long a job may hold its slot in `State::jobs_in_flight`. A job that traps
+/// after an `await` never releases its slot, so a slot that is held for longer is
+/// considered stale and is freed by `State::start_jobs`.
+pub const JOB_GUARD_LEASE: Duration = Duration::from_secs(30 * 60);
+
+/// How long the renewal of an attestation may be marked as in progress, see
+/// `RenewalGuard`.
+pub const RENEWAL_GUARD_LEASE: Duration = Duration::from_secs(10 * 60);
+
+/// How many intervals of its task a guard may be held for, see `lease`.
+const LEASE_INTERVALS: u32 = 5;
+
+/// The shortest lease, so that tasks that run often still have the time for a few
+/// outcalls.
+const MIN_TIMER_GUARD_LEASE: Duration = Duration::from_secs(2 * 60);
+
+/// How long `task` may hold its guard. A task that traps after an `await` never
+/// drops its guard, so a guard that is held for longer is considered stale and is
+/// taken over by the next run of the task. The lease is a few intervals of the task,
+/// so that a trapped task only blocks a few of its next runs.
+pub fn lease(s: &State, task: TaskType) -> Duration {
+    let interval = match task {
+        // logs are processed after each scrape
+        TaskType::ScrapeLogs | TaskType::ProcessLogs => s
+            .chains
+            .values()
+            .map(|chain| Duration::from_secs(chain.scraping_interval_secs))
+            .min()
+            .unwrap_or(SCRAPING_LOGS_INTERVAL),
+        TaskType::MonitorTransactions => MONITOR_TRANSACTIONS_INTERVAL,
+        TaskType::CheckGasBalances => CHECK_GAS_BALANCES_INTERVAL,
+        TaskType::SubmitBatches => s.batch_policy.as_ref().map_or(Duration::ZERO, |policy| {
+            Duration::from_secs(policy.window_secs)
+        }),
+    };
+    (interval * LEASE_INTERVALS).max(MIN_TIMER_GUARD_LEASE)
+}
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum TimerGuardError {
@@ -8,15 +55,27 @@ pub enum TimerGuardError {
 #[derive(Debug, PartialEq, Eq)]
 pub struct TimerGuard {
     task: TaskType,
+    acquired_at: u64,
 }
 
 impl TimerGuard {
     pub fn new(task: TaskType) -> Result<Self, TimerGuardError> {
+        let now = ic_cdk::api::time();
         mutate_state(|s| {
-            if !s.active_tasks.insert(task) {
-                return Err(TimerGuardError::AlreadyProcessing);
+            if let Some(acquired_at) = s.active_tasks.get(&task) {
+                if !is_stale(*acquired_at, lease(s, task), now) {
+                    return Err(TimerGuardError::AlreadyProcessing);
+                }
+                println!(
+                    "taking over the stale guard of {:?} acquired at {}",
+                    task, acquired_at
+                );
             }
-            Ok(Self { task })
+            s.active_tasks.insert(task, now);
+            Ok(Self {
+                task,
+                acquired_at: now,
+            })
         })
     }
 }
@@ -24,7 +83,104 @@ impl TimerGuard {
 impl Drop for TimerGuard {
     fn drop(&mut self) {
         mutate_state(|s| {
-            s.active_tasks.remove(&self.task);
+            // the guard may have been taken over or cleared in the meantime
+            if s.active_tasks.get(&self.task) == Some(&self.acquired_at) {
+                s.active_tasks.remove(&self.task);
+            }
+        });
+    }
+}
+
+/// Holds the slot of a running job in `State::jobs_in_flight`, see
+/// `State::start_jobs`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct JobGuard {
+    source: LogSource,
+    started_at: u64,
+}
+
+impl JobGuard {
+    /// Takes over the slot that `State::start_jobs` recorded for `source`.
+    pub fn new(source: LogSource, started_at: u64) -> Self {
+        Self { source, started_at }
+    }
+}
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        mutate_state(|s| {
+            if s.jobs_in_flight.get(&self.source) == Some(&self.started_at) {
+                s.jobs_in_flight.remove(&self.source);
+            }
+        });
+    }
+}
+
+/// Marks the attestation for `key` as being renewed in `State::renewals_in_progress`,
+/// so that it is not signed again while a renewal is running.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RenewalGuard {
+    key: AttestationKey,
+    started_at: u64,
+}
+
+impl RenewalGuard {
+    pub fn new(key: AttestationKey) -> Result<Self, String> {
+        let now = ic_cdk::api::time();
+        mutate_state(|s| {
+            // a renewal that trapped after an `await` never drops its guard
+            if let Some(started_at) = s.renewals_in_progress.get(&key) {
+                if !is_stale(*started_at, RENEWAL_GUARD_LEASE, now) {
+                    return Err("the attestation is already being renewed".to_string());
+                }
+            }
+            s.renewals_in_progress.insert(key, now);
+            Ok(Self {
+                key,
+                started_at: now,
+            })
+        })
+    }
+}
+
+impl Drop for RenewalGuard {
+    fn drop(&mut self) {
+        mutate_state(|s| {
+            if s.renewals_in_progress.get(&self.key) == Some(&self.started_at) {
+                s.renewals_in_progress.remove(&self.key);
+            }
         });
     }
 }
+
+pub fn is_stale(acquired_at: u64, max_age: Duration, now: u64) -> bool {
+    now.saturating_sub(acquired_at) > max_age.as_nanos() as u64
+}
+
+/// Removes the guards that have been held for longer than `max_age`, or than the
+/// lease of their task if it is `None`, and returns their tasks.
+pub fn clear_stale_guards(s: &mut State, max_age: Option<Duration>, now: u64) -> Vec<TaskType> {
+    let stale_tasks: Vec<TaskType> = s
+        .active_tasks
+        .iter()
+        .filter(|(task, acquired_at)| {
+            is_stale(
+                **acquired_at,
+                max_age.unwrap_or_else(|| lease(s, **task)),
+                now,
+            )
+        })
+        .map(|(task, _)| *task)
+        .collect();
+    for task in stale_tasks.iter() {
+        s.active_tasks.remove(task);
+    }
+    stale_tasks
+}
+
+/// A task that currently holds its guard.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ActiveTask {
+    pub task: TaskType,
+    pub acquired_at: u64,
+}
@@ -1,30 +1,348 @@
+mod batch;
 mod calculate_result;
+mod handler;
+mod monitor;
+mod new_job;
 mod read_result;
 mod submit_result;
+mod verify_payment;
 
+use alloy::primitives::{Address, B256, U256};
 use alloy::rpc::types::Log;
-use read_result::read_result;
-use submit_result::submit_result;
-
-use crate::{
-    job::calculate_result::fibonacci,
-    state::{mutate_state, LogSource},
-    Coprocessor,
-};
-// here
-pub async fn job(log_source: LogSource, log: Log) {
-    mutate_state(|s| s.record_processed_log(log_source.clone()));
-    // because we deploy the canister with topics only matching
-    // NewJob events we can safely assume that the event is a NewJob.
-    let new_job: Log<Coprocessor::NewJob> = log.log_decode().unwrap();
-    let Coprocessor::NewJob { job_id } = new_job.data();
-    // this calculation would likely exceed an ethereum blocks gas limit
-    // but can easily be calculated on the IC
-    let result = fibonacci(20);
+use ic_cdk::println;
+use read_result::{read_result, stored_result};
+use submit_result::submit_results;
+use verify_payment::verify_payment;
+
+use std::time::Duration;
+
+use crate::attestation::attest;
+use crate::cycles::{check_cycles_balance, measure, CyclesSpent};
+use crate::guard::JobGuard;
+use crate::nonce::reserve_nonce;
+use crate::state::{mutate_state, read_state, Job, JobStatus, LogSource, State};
+use crate::storage;
+pub use batch::schedule_batch_submission;
+use handler::handler_for;
+pub use monitor::{monitor_transactions, MONITOR_TRANSACTIONS_INTERVAL};
+
+/// Decodes the job id of the log with its handler, for jobs that have not been started
+/// yet.
+pub fn decode_job_id(filter_events: &[String], log: &Log) -> Option<U256> {
+    handler_for(filter_events, log)?.job_id(log).ok()
+}
+
+/// Starts queued jobs until `max_jobs_in_flight` jobs are running. Every job runs in
+/// a future of its own, so a job that waits for a slow RPC call or traps does not
+/// hold up the others.
+pub fn start_jobs() {
+    if read_state(|s| s.processing_paused) || !check_cycles_balance() {
+        return;
+    }
+    let now = ic_cdk::api::time();
+    for log_source in mutate_state(|s| s.start_jobs(now)) {
+        let guard = JobGuard::new(log_source.clone(), now);
+        ic_cdk::spawn(async move {
+            job(log_source).await;
+            drop(guard);
+            // fill the freed slot in a new message rather than recursively
+            if read_state(State::has_logs_to_process) {
+                ic_cdk_timers::set_timer(Duration::ZERO, start_jobs);
+            }
+        });
+    }
+}
+
+/// Runs the job for `log_source`, resuming from its last recorded status.
+pub async fn job(log_source: LogSource) {
+    let Some(job) = read_state(|s| s.logs_to_process.get(&log_source).cloned()) else {
+        return;
+    };
+    // failed jobs are retried once their backoff has passed
+    if job.next_attempt_at > ic_cdk::api::time() {
+        return;
+    }
+    match job.status {
+        JobStatus::Pending | JobStatus::Computing | JobStatus::Computed => {
+            compute_and_submit(log_source, job).await
+        }
+        // submitted jobs are confirmed or resubmitted by `monitor_transactions`
+        JobStatus::Submitted { .. } => {}
+        status => mutate_state(|s| s.record_processed_log(log_source, status)),
+    }
+}
+
+async fn compute_and_submit(log_source: LogSource, job: Job) {
+    // the payment is verified once, before the job is computed
+    if job.status == JobStatus::Pending {
+        let policy = read_state(|s| s.payment_policy.clone());
+        // jobs without a handler are recorded as unhandled below
+        let handler = read_state(|s| handler_for(&s.chain(job.chain_id).filter_events, &job.log));
+        if let (Some(policy), Some(handler)) = (policy, handler) {
+            let result_chain_id = read_state(|s| s.chain(job.chain_id).result_chain_id());
+            let (payment, cycles) =
+                measure(verify_payment(&job, handler, result_chain_id, &policy)).await;
+            mutate_state(|s| s.record_job_cycles(&log_source, CyclesSpent::rpc(cycles)));
+            if !is_queued(&log_source) {
+                return;
+            }
+            match payment {
+                Ok(payment) if payment.paid < payment.price => {
+                    println!(
+                        "job {:?} paid {} wei but costs {} wei",
+                        log_source, payment.paid, payment.price
+                    );
+                    return mutate_state(|s| {
+                        s.record_processed_log(
+                            log_source,
+                            JobStatus::Rejected {
+                                paid: payment.paid,
+                                price: payment.price,
+                            },
+                        )
+                    });
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    return retry_later(log_source, format!("failed to verify the payment: {}", e))
+                }
+            }
+        }
+    }
+
+    let (job_id, result) = match (job.job_id, job.result) {
+        (Some(job_id), Some(result)) => (job_id, result),
+        _ => {
+            // the handler is chosen by the event signature (topic0) of the log
+            let Some(handler) =
+                read_state(|s| handler_for(&s.chain(job.chain_id).filter_events, &job.log))
+            else {
+                println!("no handler for log {:?}", log_source);
+                return mutate_state(|s| s.record_processed_log(log_source, JobStatus::Unhandled));
+            };
+            let job_id = match handler.job_id(&job.log) {
+                Ok(job_id) => job_id,
+                Err(e) => return fail(log_source, format!("failed to decode log: {}", e)),
+            };
+            let result = match handler.compute(&job.log) {
+                Ok(result) => result,
+                Err(e) => return fail(log_source, format!("failed to compute result: {}", e)),
+            };
+            mutate_state(|s| {
+                s.update_job(&log_source, |job| {
+                    job.status = JobStatus::Computing;
+                    job.job_id = Some(job_id);
+                    job.result = Some(result.clone());
+                })
+            });
+            (job_id, result)
+        }
+    };
+
+    // the result is signed for users to relay instead of being sent by the canister
+    if !read_state(|s| s.sends_result_transaction(&job)) {
+        let result_chain_id = job
+            .result_chain_id
+            .unwrap_or_else(|| read_state(|s| s.chain(job.chain_id).result_chain_id()));
+        let requester =
+            match read_state(|s| handler_for(&s.chain(job.chain_id).filter_events, &job.log))
+                .ok_or_else(|| "there is no handler for the log".to_string())
+                .and_then(|handler| handler.requester(&job.log))
+            {
+                Ok(requester) => requester,
+                Err(e) => return fail(log_source, format!("failed to decode log: {}", e)),
+            };
+        return attest_result(log_source, result_chain_id, job_id, requester, result).await;
+    }
+
+    // with batching, the result is submitted together with others by `submit_batches`
+    if read_state(|s| s.batch_policy.is_some()) {
+        mutate_state(|s| s.update_job(&log_source, |job| job.status = JobStatus::Computed));
+        return schedule_batch_submission();
+    }
+
+    // the nonce is reserved right before the transaction is signed and recorded before
+    // it is sent, so that a job that is resumed after its transaction may have been
+    // sent replaces it instead of sending a new one
+    let (result_chain_id, nonce) = match (job.result_chain_id, job.nonce) {
+        (Some(result_chain_id), Some(nonce)) => (result_chain_id, nonce),
+        _ => {
+            // the result may be posted to another chain than the one the log is from
+            let result_chain_id = read_state(|s| s.chain(job.chain_id).result_chain_id());
+            let (reserved, cycles) =
+                measure(reserve_nonce(result_chain_id, log_source.clone())).await;
+            mutate_state(|s| s.record_job_cycles(&log_source, CyclesSpent::rpc(cycles)));
+            match reserved {
+                Ok(nonce) => {
+                    if !mutate_state(|s| s.record_job_nonce(&log_source, result_chain_id, nonce)) {
+                        return;
+                    }
+                    (result_chain_id, nonce)
+                }
+                Err(e) => {
+                    return retry_later(log_source, format!("failed to reserve a nonce: {}", e))
+                }
+            }
+        }
+    };
+
     // we write the result back to the evm smart contract, creating a signature
     // on the transaction with chain key ecdsa and sending it to the evm via the
     // evm rpc canister
-    submit_result(result.to_string(), *job_id).await;
+    let (submitted, cycles) = measure(submit_results(
+        result_chain_id,
+        &[(job_id, result)],
+        nonce,
+        None,
+    ))
+    .await;
+    mutate_state(|s| {
+        s.record_job_cycles(
+            &log_source,
+            CyclesSpent::transaction(cycles, submitted.is_ok()),
+        )
+    });
+    match submitted {
+        Ok((tx_hash, fees)) => mutate_state(|s| {
+            s.record_job_sent(
+                &log_source,
+                result_chain_id,
+                nonce,
+                tx_hash,
+                fees,
+                ic_cdk::api::time(),
+            )
+        }),
+        // a reverted job released its nonce already
+        Err(e) => {
+            if !e.may_be_sent && is_queued(&log_source) {
+                mutate_state(|s| s.release_job_nonce(&log_source));
+            }
+            retry_later(log_source, format!("failed to submit result: {}", e))
+        }
+    }
+}
+
+/// Signs the result with the canister's threshold ECDSA key and stores the
+/// attestation, see `ResultSink::Attestation`.
+async fn attest_result(
+    log_source: LogSource,
+    result_chain_id: u64,
+    job_id: U256,
+    requester: Address,
+    result: String,
+) {
+    let (attestation, cycles) = measure(attest(result_chain_id, job_id, requester, result)).await;
+    mutate_state(|s| {
+        s.record_job_cycles(
+            &log_source,
+            CyclesSpent::transaction(cycles, attestation.is_ok()),
+        )
+    });
+    match attestation {
+        Ok(attestation) => {
+            // the job may have been reverted by a reorg while it was signed, and its
+            // job id taken by another one
+            mutate_state(|s| {
+                if let Some(job) = s.logs_to_process.get_mut(&log_source) {
+                    job.result_chain_id = Some(result_chain_id);
+                    storage::record_attestation(attestation);
+                    s.record_processed_log(log_source, JobStatus::Attested);
+                    println!("Successfully attested the result of job {}", job_id);
+                }
+            })
+        }
+        Err(e) => retry_later(log_source, format!("failed to attest result: {}", e)),
+    }
+}
+
+/// Records the job as confirmed once the result transaction `tx_hash` was mined
+/// successfully.
+async fn confirm(
+    log_source: LogSource,
+    result_chain_id: u64,
+    job_id: U256,
+    tx_hash: B256,
+    gas_used: u128,
+) {
+    mutate_state(|s| {
+        s.update_job(&log_source, |job| job.gas_used = Some(gas_used));
+        s.record_processed_log(log_source, JobStatus::Confirmed { tx_hash })
+    });
+    println!("Successfully ran job {}, tx: {}", job_id, tx_hash);
     // `read_result` demonstrates how to make a `eth_call` via the evm rpc canister
-    read_result(*job_id).await;
+    let ((), cycles) = measure(read_result(result_chain_id, job_id)).await;
+    mutate_state(|s| s.cycles_spent += CyclesSpent::rpc(cycles));
+}
+
+/// Whether the job for `log_source` is still queued. A reorg may revert a job while it
+/// awaits a call, after which nothing may be recorded for it.
+fn is_queued(log_source: &LogSource) -> bool {
+    read_state(|s| s.logs_to_process.contains_key(log_source))
+}
+
+/// Fails the job for good, for errors that retrying cannot fix.
+fn fail(log_source: LogSource, reason: String) {
+    println!("job {:?} failed: {}", log_source, reason);
+    mutate_state(|s| {
+        if s.logs_to_process.contains_key(&log_source) {
+            s.record_processed_log(log_source, JobStatus::Failed { reason })
+        }
+    });
+}
+
+/// Records a failed attempt, the job is retried with exponential backoff until it runs
+/// out of attempts and is moved to the dead letter jobs.
+fn retry_later(log_source: LogSource, reason: String) {
+    println!("job {:?} failed, retrying later: {}", log_source, reason);
+    mutate_state(|s| {
+        if s.logs_to_process.contains_key(&log_source) {
+            s.record_job_failure(log_source, reason, ic_cdk::api::time())
+        }
+    });
+}
+
+/// Called instead of `job` for a processed job whose log was reverted by a reorg and
+/// then re-emitted, if the canister runs with `ReorgPolicy::Compensate`. The result of
+/// the first run was submitted against a block that no longer exists. If the
+/// coprocessor contract holds a result for the job anyway, e.g. because the result
+/// transaction was re-included, the job is recorded as compensated. Otherwise the
+/// result was lost with the reorg and the job is queued to run again.
+pub async fn compensate(log_source: LogSource, job: Job) {
+    let job_id = read_state(|s| handler_for(&s.chain(job.chain_id).filter_events, &job.log))
+        .and_then(|handler| handler.job_id(&job.log).ok());
+    // the first run did not post a result for a log that no handler can decode
+    let Some(job_id) = job_id else {
+        return mutate_state(|s| s.record_compensated_log(log_source));
+    };
+    let result_chain_id = read_state(|s| s.chain(job.chain_id).result_chain_id());
+    let (stored, cycles) = measure(stored_result(result_chain_id, job_id)).await;
+    mutate_state(|s| s.cycles_spent += CyclesSpent::rpc(cycles));
+    let stored = match stored {
+        Ok(stored) => stored,
+        Err(e) => {
+            // the job stays in `logs_to_compensate` and is compensated with the next
+            // run of `process_logs`
+            println!(
+                "failed to read the result of job {} to compensate {:?}: {}",
+                job_id, log_source, e
+            );
+            return;
+        }
+    };
+    // the log may have been reverted again while the result was read
+    if !read_state(|s| s.logs_to_compensate.contains_key(&log_source)) {
+        return;
+    }
+    if stored.is_empty() {
+        println!(
+            "the result of job {} was lost with a reorg, running it again",
+            job_id
+        );
+        mutate_state(|s| s.requeue_compensated_log(log_source));
+        start_jobs();
+    } else {
+        println!("the result of job {} survived the reorg", job_id);
+        mutate_state(|s| s.record_compensated_log(log_source));
+    }
 }
@@ -0,0 +1,236 @@
+use std::time::Duration;
+
+use alloy::eips::BlockId;
+use alloy::primitives::U256;
+use ic_cdk::println;
+
+use super::submit_result::{replay_results, submit_results};
+use crate::cycles::{measure, CyclesSpent};
+use crate::guard::TimerGuard;
+use crate::nonce::reserve_nonce;
+use crate::state::{mutate_state, read_state, JobStatus, LogSource, TaskType};
+
+/// Schedules the submission of the computed results according to the
+/// `batch_policy`: right away once a full batch is ready, otherwise when the batch
+/// window has passed.
+pub fn schedule_batch_submission() {
+    let Some(policy) = read_state(|s| s.batch_policy.clone()) else {
+        return;
+    };
+    let computed_jobs = read_state(|s| s.computed_jobs());
+    if computed_jobs.is_empty() {
+        return;
+    }
+    let is_full = computed_jobs
+        .values()
+        .any(|sources| sources.len() >= policy.max_batch_size as usize);
+    if !is_full && read_state(|s| s.batch_timer.is_some()) {
+        return;
+    }
+    let delay = if is_full {
+        Duration::ZERO
+    } else {
+        Duration::from_secs(policy.window_secs)
+    };
+    let timer_id = ic_cdk_timers::set_timer(delay, || {
+        mutate_state(|s| s.batch_timer = None);
+        ic_cdk::spawn(submit_batches())
+    });
+    if let Some(previous_timer_id) = mutate_state(|s| s.batch_timer.replace(timer_id)) {
+        ic_cdk_timers::clear_timer(previous_timer_id);
+    }
+}
+
+/// Submits the computed results of each result chain in batches of at most
+/// `max_batch_size` jobs.
+async fn submit_batches() {
+    let guard = match TimerGuard::new(TaskType::SubmitBatches) {
+        Ok(guard) => guard,
+        Err(_) => return,
+    };
+    let Some(policy) = read_state(|s| s.batch_policy.clone()) else {
+        return;
+    };
+    if read_state(|s| s.processing_paused) {
+        return;
+    }
+
+    let now = ic_cdk::api::time();
+    let batches = read_state(|s| s.batches(policy.max_batch_size, now));
+    for (result_chain_id, sources) in batches {
+        submit_batch(result_chain_id, sources).await;
+    }
+
+    // results that are left over or failed are submitted with the next batch
+    drop(guard);
+    schedule_batch_submission();
+}
+
+async fn submit_batch(result_chain_id: u64, sources: Vec<LogSource>) {
+    // jobs may have been reverted by a reorg in the meantime
+    let jobs: Vec<(LogSource, U256, String)> = read_state(|s| {
+        sources
+            .into_iter()
+            .filter_map(|source| {
+                let job = s.logs_to_process.get(&source)?;
+                if job.status != JobStatus::Computed {
+                    return None;
+                }
+                Some((source, job.job_id?, job.result.clone()?))
+            })
+            .collect()
+    });
+    let Some((owner, _, _)) = jobs.first() else {
+        return;
+    };
+    let sources: Vec<LogSource> = jobs.iter().map(|(source, _, _)| source.clone()).collect();
+
+    // a batch whose transaction may have been sent replaces it with the same nonce,
+    // like a single job that is resumed
+    let nonce = match read_state(|s| s.logs_to_process[owner].nonce) {
+        Some(nonce) => nonce,
+        None => {
+            let (reserved, cycles) = measure(reserve_nonce(result_chain_id, owner.clone())).await;
+            record_batch_cycles(&sources, CyclesSpent::rpc(cycles));
+            match reserved {
+                Ok(nonce) => nonce,
+                Err(e) => {
+                    return retry_batch_later(&sources, format!("failed to reserve a nonce: {}", e))
+                }
+            }
+        }
+    };
+    // the nonce is recorded before the transaction is sent, like for single jobs
+    mutate_state(|s| {
+        for source in sources.iter() {
+            if let Some(job) = s.logs_to_process.get_mut(source) {
+                job.result_chain_id = Some(result_chain_id);
+                job.nonce = Some(nonce);
+            }
+        }
+    });
+
+    let results: Vec<(U256, String)> = jobs
+        .iter()
+        .map(|(_, job_id, result)| (*job_id, result.clone()))
+        .collect();
+    let (submitted, cycles) = measure(submit_results(result_chain_id, &results, nonce, None)).await;
+    record_batch_cycles(
+        &sources,
+        CyclesSpent::transaction(cycles, submitted.is_ok()),
+    );
+    match submitted {
+        Ok((tx_hash, fees)) => {
+            println!(
+                "submitted the results of {} jobs in transaction {}",
+                sources.len(),
+                tx_hash
+            );
+            mutate_state(|s| {
+                let chain = s.chain_mut(result_chain_id);
+                chain.nonces.record_sent(nonce, tx_hash);
+                chain.spend_gas_balance(sources.len() as u64);
+                for source in sources.iter() {
+                    if let Some(job) = s.logs_to_process.get_mut(source) {
+                        job.status = JobStatus::Submitted { tx_hash };
+                        job.fees = Some(fees);
+                        job.submitted_at = ic_cdk::api::time();
+                    }
+                }
+            })
+        }
+        // the jobs keep the nonce, as it may be used by the transaction
+        Err(e) if e.may_be_sent => {
+            retry_batch_later(&sources, format!("failed to submit the batch: {}", e))
+        }
+        Err(e) => {
+            mutate_state(|s| {
+                for source in sources.iter() {
+                    if s.logs_to_process.contains_key(source) {
+                        s.release_job_nonce(source);
+                    }
+                }
+            });
+            retry_failed_results(
+                result_chain_id,
+                &jobs,
+                format!("failed to submit the batch: {}", e),
+            )
+            .await
+        }
+    }
+}
+
+/// Replays the result call of every job of a batch that could not be sent on its own,
+/// so that only the jobs whose result call reverts are retried later and the others
+/// are sent with the next batch. If no single result reverts, the whole batch is
+/// retried later.
+async fn retry_failed_results(
+    result_chain_id: u64,
+    jobs: &[(LogSource, U256, String)],
+    reason: String,
+) {
+    let sources: Vec<LogSource> = jobs.iter().map(|(source, _, _)| source.clone()).collect();
+    if jobs.len() < 2 {
+        return retry_batch_later(&sources, reason);
+    }
+    let mut failed = vec![];
+    for (source, job_id, result) in jobs {
+        let (replayed, cycles) = measure(replay_results(
+            result_chain_id,
+            &[(*job_id, result.clone())],
+            BlockId::latest(),
+        ))
+        .await;
+        mutate_state(|s| s.record_job_cycles(source, CyclesSpent::rpc(cycles)));
+        if let Ok(Some(revert_reason)) = replayed {
+            failed.push((source.clone(), revert_reason));
+        }
+    }
+    if failed.is_empty() {
+        return retry_batch_later(&sources, reason);
+    }
+    let now = ic_cdk::api::time();
+    mutate_state(|s| {
+        for (source, revert_reason) in failed {
+            println!(
+                "the result call of job {:?} reverts, retrying it later: {}",
+                source, revert_reason
+            );
+            if s.logs_to_process.contains_key(&source) {
+                s.record_job_failure(
+                    source,
+                    format!("{}: the result call reverts: {}", reason, revert_reason),
+                    now,
+                );
+            }
+        }
+    });
+}
+
+/// Charges every job of the batch its share of `cycles`.
+fn record_batch_cycles(sources: &[LogSource], cycles: CyclesSpent) {
+    let share = cycles.share(sources.len());
+    mutate_state(|s| {
+        for source in sources {
+            s.record_job_cycles(source, share);
+        }
+    });
+}
+
+/// Records a failed attempt for every job of the batch that is still queued.
+fn retry_batch_later(sources: &[LogSource], reason: String) {
+    println!(
+        "batch of {} jobs failed, retrying later: {}",
+        sources.len(),
+        reason
+    );
+    let now = ic_cdk::api::time();
+    mutate_state(|s| {
+        for source in sources {
+            if s.logs_to_process.contains_key(source) {
+                s.record_job_failure(source.clone(), reason.clone(), now);
+            }
+        }
+    });
+}
@@ -0,0 +1,44 @@
+use alloy::primitives::{keccak256, Address, U256};
+use alloy::rpc::types::Log;
+
+use super::new_job::{LegacyNewJobHandler, NewJobHandler};
+
+/// Processes the logs of one event type. To handle a new event type, implement this
+/// trait, add the handler to `HANDLERS` and the event signature to `filter_events`.
+pub trait JobHandler: Sync {
+    /// The event signature as passed in `filter_events`, e.g.
+    /// `NewJob(uint256,address,uint256)`.
+    fn event_signature(&self) -> &'static str;
+
+    /// Decodes the id of the job from the log.
+    fn job_id(&self, log: &Log) -> Result<U256, String>;
+
+    /// Decodes the account that created the job from the log. Attested results are
+    /// bound to it, see `attestation::attest`.
+    fn requester(&self, log: &Log) -> Result<Address, String>;
+
+    /// Decodes what the job paid from the log, in wei, see `PaymentPolicy`. Returns
+    /// `None` if the event does not emit it, in which case the value of the transaction
+    /// that emitted the log is taken instead.
+    fn payment(&self, log: &Log) -> Result<Option<U256>, String>;
+
+    /// Computes the result of the job, which is written back to the coprocessor
+    /// contract.
+    fn compute(&self, log: &Log) -> Result<String, String>;
+}
+
+/// All known handlers.
+static HANDLERS: &[&dyn JobHandler] = &[&NewJobHandler, &LegacyNewJobHandler];
+
+/// Returns the handler for the log's event (topic0), if the event is one of
+/// `filter_events` and a handler for it exists.
+pub fn handler_for(filter_events: &[String], log: &Log) -> Option<&'static dyn JobHandler> {
+    let topic0 = log.topic0()?;
+    HANDLERS.iter().copied().find(|handler| {
+        let signature = handler.event_signature();
+        keccak256(signature) == *topic0
+            && filter_events
+                .iter()
+                .any(|event| event.as_str() == signature)
+    })
+}
@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+use std::iter;
+use std::time::Duration;
+
+use alloy::primitives::{B256, U256};
+use alloy::rpc::types::{Log, TransactionReceipt};
+use ic_cdk::println;
+
+use super::confirm;
+use super::submit_result::{get_receipt, revert_reason, submit_results};
+use crate::cycles::{check_cycles_balance, measure, CyclesSpent};
+use crate::guard::TimerGuard;
+use crate::state::{mutate_state, read_state, Job, JobStatus, LogSource, TaskType};
+use crate::Coprocessor;
+
+/// How often the receipts of the submitted result transactions are polled.
+pub const MONITOR_TRANSACTIONS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Polls the receipts of the result transactions of all submitted jobs. Jobs whose
+/// transaction was mined are confirmed if it succeeded and recorded as reverted
+/// otherwise, transactions that stay unmined for longer than the
+/// `resubmission_policy` allows are replaced with higher fees.
+pub async fn monitor_transactions() {
+    let _guard = match TimerGuard::new(TaskType::MonitorTransactions) {
+        Ok(guard) => guard,
+        Err(_) => return,
+    };
+    if !check_cycles_balance() {
+        return;
+    }
+
+    // the jobs of a batch share their transaction and thereby its nonce
+    let submissions: BTreeMap<(u64, u64), Vec<LogSource>> = read_state(|s| {
+        let mut submissions: BTreeMap<(u64, u64), Vec<LogSource>> = BTreeMap::new();
+        for (source, job) in s.logs_to_process.iter() {
+            if let (JobStatus::Submitted { .. }, Some(result_chain_id), Some(nonce)) =
+                (&job.status, job.result_chain_id, job.nonce)
+            {
+                submissions
+                    .entry((result_chain_id, nonce))
+                    .or_default()
+                    .push(source.clone());
+            }
+        }
+        submissions
+    });
+
+    for ((result_chain_id, nonce), sources) in submissions {
+        // the jobs may have been reverted by a reorg in the meantime
+        let jobs: Vec<(LogSource, Job)> = read_state(|s| {
+            sources
+                .into_iter()
+                .filter_map(|source| {
+                    let job = s.logs_to_process.get(&source)?.clone();
+                    matches!(job.status, JobStatus::Submitted { .. }).then_some((source, job))
+                })
+                .collect()
+        });
+        if !jobs.is_empty() {
+            check_transaction(result_chain_id, nonce, jobs).await;
+        }
+    }
+}
+
+async fn check_transaction(result_chain_id: u64, nonce: u64, jobs: Vec<(LogSource, Job)>) {
+    let (_, first_job) = &jobs[0];
+    let JobStatus::Submitted { tx_hash } = first_job.status else {
+        return;
+    };
+    let sources: Vec<LogSource> = jobs.iter().map(|(source, _)| source.clone()).collect();
+    let results: Vec<(U256, String)> = jobs
+        .iter()
+        .map(|(_, job)| {
+            (
+                job.job_id.expect("BUG: submitted job without job id"),
+                job.result
+                    .clone()
+                    .expect("BUG: submitted job without result"),
+            )
+        })
+        .collect();
+
+    // a replaced transaction can still be mined instead of its replacement
+    for hash in iter::once(tx_hash).chain(first_job.replaced_tx_hashes.iter().copied()) {
+        let (receipt, cycles) = measure(get_receipt(result_chain_id, hash)).await;
+        record_cycles(&sources, CyclesSpent::rpc(cycles));
+        match receipt {
+            Ok(Some(receipt)) if receipt.status() => {
+                return record_mined(result_chain_id, &jobs, hash, &receipt).await
+            }
+            Ok(Some(receipt)) => {
+                let (reason, cycles) = measure(revert_reason(
+                    result_chain_id,
+                    &results,
+                    receipt.block_number,
+                ))
+                .await;
+                println!(
+                    "transaction {} of {} jobs reverted: {}",
+                    hash,
+                    jobs.len(),
+                    reason
+                );
+                record_cycles(&sources, CyclesSpent::rpc(cycles));
+                let gas_used = receipt.gas_used / jobs.len() as u128;
+                return mutate_state(|s| {
+                    for source in sources {
+                        if !s.logs_to_process.contains_key(&source) {
+                            continue;
+                        }
+                        s.update_job(&source, |job| job.gas_used = Some(gas_used));
+                        s.record_processed_log(
+                            source,
+                            JobStatus::ExecutionReverted {
+                                tx_hash: hash,
+                                reason: reason.clone(),
+                            },
+                        );
+                    }
+                });
+            }
+            Ok(None) => {}
+            Err(e) => {
+                println!("failed to get the receipt of transaction {}: {}", hash, e);
+                return;
+            }
+        }
+    }
+
+    let policy = read_state(|s| s.resubmission_policy.clone());
+    let timeout = Duration::from_secs(policy.timeout_secs).as_nanos() as u64;
+    if ic_cdk::api::time() < first_job.submitted_at.saturating_add(timeout) {
+        return;
+    }
+
+    // the replacement reuses the nonce, so only one of the transactions can be mined
+    let min_fees = first_job
+        .fees
+        .map(|fees| fees.bump(policy.fee_bump_percent));
+    let (submitted, cycles) =
+        measure(submit_results(result_chain_id, &results, nonce, min_fees)).await;
+    record_cycles(
+        &sources,
+        CyclesSpent::transaction(cycles, submitted.is_ok()),
+    );
+    match submitted {
+        Ok((new_tx_hash, fees)) => {
+            println!(
+                "replaced transaction {} of {} jobs with {}",
+                tx_hash,
+                jobs.len(),
+                new_tx_hash
+            );
+            mutate_state(|s| {
+                s.chain_mut(result_chain_id)
+                    .nonces
+                    .record_sent(nonce, new_tx_hash);
+                for source in sources.iter() {
+                    if let Some(job) = s.logs_to_process.get_mut(source) {
+                        job.replaced_tx_hashes.push(tx_hash);
+                        job.status = JobStatus::Submitted {
+                            tx_hash: new_tx_hash,
+                        };
+                        job.fees = Some(fees);
+                        job.submitted_at = ic_cdk::api::time();
+                    }
+                }
+            });
+        }
+        // the transaction is checked again with the next poll
+        Err(e) => println!("failed to replace transaction {}: {}", tx_hash, e),
+    }
+}
+
+/// Confirms the jobs of a successful result transaction, except for those whose
+/// result the contract reported as failed with a `CallbackFailed` event.
+async fn record_mined(
+    result_chain_id: u64,
+    jobs: &[(LogSource, Job)],
+    tx_hash: B256,
+    receipt: &TransactionReceipt,
+) {
+    let failures: BTreeMap<U256, String> = receipt
+        .inner
+        .logs()
+        .iter()
+        .filter_map(|log| {
+            let failed: Log<Coprocessor::CallbackFailed> = log.log_decode().ok()?;
+            Some((failed.data().job_id, failed.data().reason.clone()))
+        })
+        .collect();
+    // the gas of a batch is shared by its jobs, and the next callbacks are estimated
+    // to need as much as one share each
+    let gas_used = receipt.gas_used / jobs.len() as u128;
+    mutate_state(|s| s.chain_mut(result_chain_id).last_callback_gas_used = Some(gas_used));
+
+    for (log_source, job) in jobs {
+        // confirming a job awaits a call, during which the others may be reverted
+        if !read_state(|s| s.logs_to_process.contains_key(log_source)) {
+            continue;
+        }
+        let job_id = job.job_id.expect("BUG: submitted job without job id");
+        match failures.get(&job_id) {
+            Some(reason) => {
+                println!("result of job {} failed in {}: {}", job_id, tx_hash, reason);
+                mutate_state(|s| {
+                    s.update_job(log_source, |job| job.gas_used = Some(gas_used));
+                    s.record_processed_log(
+                        log_source.clone(),
+                        JobStatus::ExecutionReverted {
+                            tx_hash,
+                            reason: reason.clone(),
+                        },
+                    )
+                });
+            }
+            None => {
+                confirm(
+                    log_source.clone(),
+                    result_chain_id,
+                    job_id,
+                    tx_hash,
+                    gas_used,
+                )
+                .await
+            }
+        }
+    }
+}
+
+/// Charges every job of the transaction its share of `cycles`.
+fn record_cycles(sources: &[LogSource], cycles: CyclesSpent) {
+    let share = cycles.share(sources.len());
+    mutate_state(|s| {
+        for source in sources {
+            s.record_job_cycles(source, share);
+        }
+    });
+}
@@ -0,0 +1,77 @@
+use alloy::primitives::{Address, U256};
+use alloy::rpc::types::Log;
+use alloy::sol_types::SolEvent;
+
+use super::calculate_result::fibonacci;
+use super::handler::JobHandler;
+use crate::Coprocessor;
+
+/// Handles the `NewJob` events of the `Coprocessor` contract.
+pub struct NewJobHandler;
+
+impl JobHandler for NewJobHandler {
+    fn event_signature(&self) -> &'static str {
+        Coprocessor::NewJob::SIGNATURE
+    }
+
+    fn job_id(&self, log: &Log) -> Result<U256, String> {
+        let new_job: Log<Coprocessor::NewJob> = log.log_decode().map_err(|e| e.to_string())?;
+        Ok(new_job.data().job_id)
+    }
+
+    fn requester(&self, log: &Log) -> Result<Address, String> {
+        let new_job: Log<Coprocessor::NewJob> = log.log_decode().map_err(|e| e.to_string())?;
+        Ok(new_job.data().requester)
+    }
+
+    fn payment(&self, log: &Log) -> Result<Option<U256>, String> {
+        let new_job: Log<Coprocessor::NewJob> = log.log_decode().map_err(|e| e.to_string())?;
+        Ok(Some(new_job.data().value))
+    }
+
+    fn compute(&self, _log: &Log) -> Result<String, String> {
+        // this calculation would likely exceed an ethereum blocks gas limit
+        // but can easily be calculated on the IC
+        Ok(fibonacci(20).to_string())
+    }
+}
+
+mod legacy {
+    alloy::sol! {
+        /// The `NewJob` event of coprocessor contracts deployed before it emitted the
+        /// requester and the value of the job.
+        event NewJob(uint indexed job_id);
+    }
+}
+
+/// Handles the `NewJob(uint256)` events of coprocessor contracts deployed before the
+/// event emitted the requester and the value, so that canisters whose
+/// `filter_events` still name it keep running their jobs.
+pub struct LegacyNewJobHandler;
+
+impl JobHandler for LegacyNewJobHandler {
+    fn event_signature(&self) -> &'static str {
+        legacy::NewJob::SIGNATURE
+    }
+
+    fn job_id(&self, log: &Log) -> Result<U256, String> {
+        let new_job: Log<legacy::NewJob> = log.log_decode().map_err(|e| e.to_string())?;
+        Ok(new_job.data().job_id)
+    }
+
+    fn requester(&self, _log: &Log) -> Result<Address, String> {
+        Err(format!(
+            "{} does not emit the requester",
+            legacy::NewJob::SIGNATURE
+        ))
+    }
+
+    // the value of the transaction is taken instead
+    fn payment(&self, _log: &Log) -> Result<Option<U256>, String> {
+        Ok(None)
+    }
+
+    fn compute(&self, log: &Log) -> Result<String, String> {
+        NewJobHandler.compute(log)
+    }
+}
@@ -1,23 +1,34 @@
 use alloy::{primitives::Uint, providers::ProviderBuilder, transports::icp::IcpConfig};
 use ic_cdk::println;
 
-use crate::{
-    state::{read_state, State},
-    Coprocessor,
-};
+use crate::{state::read_state, Coprocessor};
 
-pub async fn read_result(job_id: Uint<256, 4>) {
-    let rpc_service = read_state(|s| s.rpc_service.clone());
+pub async fn read_result(chain_id: u64, job_id: Uint<256, 4>) {
+    let rpc_service = read_state(|s| s.chain(chain_id).rpc_service.clone());
     let config = IcpConfig::new(rpc_service);
     let provider = ProviderBuilder::new().on_icp(config);
-    let contract_address = read_state(State::get_filter_addresses)[0];
+    let contract_address = read_state(|s| s.chain(chain_id).coprocessor_evm_address);
     let contract = Coprocessor::new(contract_address, provider);
 
     let response = contract.getResult(job_id).call().await;
 
-    let result = match response {
-        Ok(result) => result._0,
-        Err(e) => panic!("{}", e.to_string()),
-    };
-    println!("Result: {}", result);
+    match response {
+        Ok(result) => println!("Result: {}", result._0),
+        Err(e) => println!("failed to read result of job {}: {}", job_id, e),
+    }
+}
+
+/// Returns the result that the coprocessor contract on `chain_id` holds for `job_id`,
+/// which is empty if none was stored.
+pub async fn stored_result(chain_id: u64, job_id: Uint<256, 4>) -> Result<String, String> {
+    let rpc_service = read_state(|s| s.chain(chain_id).rpc_service.clone());
+    let provider = ProviderBuilder::new().on_icp(IcpConfig::new(rpc_service));
+    let contract_address = read_state(|s| s.chain(chain_id).coprocessor_evm_address);
+    let contract = Coprocessor::new(contract_address, provider);
+    let response = contract
+        .getResult(job_id)
+        .call()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(response._0)
 }
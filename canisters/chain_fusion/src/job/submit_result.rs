@@ -1,73 +1,183 @@
-use alloy::primitives::Uint;
+use alloy::contract::Error as ContractError;
+use alloy::eips::BlockId;
+use alloy::primitives::{TxHash, U256};
 use alloy::providers::Provider;
+use alloy::rpc::json_rpc::RpcError;
+use alloy::rpc::types::TransactionReceipt;
+use alloy::sol_types::decode_revert_reason;
 use alloy::{network::EthereumWallet, providers::ProviderBuilder, transports::icp::IcpConfig};
-use ic_cdk::println;
 
-use crate::state::{mutate_state, read_state};
+use crate::state::{read_state, GasFees};
 use crate::Coprocessor;
 
-pub async fn submit_result(result: String, job_id: Uint<256, 4>) {
+/// The reason why `submit_results` failed, and whether the transaction may have been
+/// broadcast anyway, in which case its nonce may be used.
+#[derive(Debug)]
+pub struct SubmitError {
+    pub reason: String,
+    pub may_be_sent: bool,
+}
+
+impl SubmitError {
+    fn not_sent(reason: impl ToString) -> Self {
+        Self {
+            reason: reason.to_string(),
+            may_be_sent: false,
+        }
+    }
+}
+
+impl std::fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+
+/// Sends the result transaction to the coprocessor contract on `chain_id` with the
+/// current fee estimate, but at least `min_fees`. A single result is sent to
+/// `callback`, several results are sent in one transaction to `callbackBatch`.
+/// Returns the transaction hash and the fees it was sent with.
+pub async fn submit_results(
+    chain_id: u64,
+    results: &[(U256, String)],
+    nonce: u64,
+    min_fees: Option<GasFees>,
+) -> Result<(TxHash, GasFees), SubmitError> {
     // get necessary global state
-    let signer = read_state(|s| s.signer.clone()).unwrap();
-    let evm_address = read_state(|s| s.canister_evm_address).unwrap();
+    let signer = read_state(|s| s.signer.clone())
+        .ok_or_else(|| SubmitError::not_sent("the signer is not initialized yet"))?;
+    let evm_address = read_state(|s| s.canister_evm_address).ok_or_else(|| {
+        SubmitError::not_sent("the canister's EVM address is not initialized yet")
+    })?;
     let wallet = EthereumWallet::new(signer);
-    let rpc_service = read_state(|s| s.rpc_service.clone());
-    let chain_id = read_state(|s| s.chain_id);
+    let rpc_service = read_state(|s| s.chain(chain_id).rpc_service.clone());
     let config = IcpConfig::new(rpc_service);
     let provider = ProviderBuilder::new()
         .with_gas_estimation()
         .wallet(wallet)
         .on_icp(config);
-    let contract_address = read_state(|s| s.coprocessor_evm_address);
-    let contract = Coprocessor::new(contract_address, provider.clone());
+    let estimate = provider
+        .estimate_eip1559_fees(None)
+        .await
+        .map_err(SubmitError::not_sent)?;
+    let mut fees = GasFees {
+        max_fee_per_gas: estimate.max_fee_per_gas,
+        max_priority_fee_per_gas: estimate.max_priority_fee_per_gas,
+    };
+    if let Some(min_fees) = min_fees {
+        fees = fees.max(min_fees);
+    }
+    let contract_address = read_state(|s| s.chain(chain_id).coprocessor_evm_address);
+    let contract = Coprocessor::new(contract_address, provider);
 
-    // Attempt to get nonce from thread-local storage
-    let maybe_nonce = read_state(|s| {
-        // If a nonce exists, the next nonce to use is latest nonce + 1
-        s.nonce.map(|nonce| nonce + 1)
+    // the gas is estimated up front, so that a result call that would revert fails
+    // before anything is signed and sent. An error from `send` may be returned after
+    // the transaction was broadcast.
+    let pending_tx = match results {
+        [(job_id, result)] => {
+            let call = contract
+                .callback(result.clone(), *job_id)
+                .nonce(nonce)
+                .max_fee_per_gas(fees.max_fee_per_gas)
+                .max_priority_fee_per_gas(fees.max_priority_fee_per_gas)
+                .from(evm_address)
+                .chain_id(chain_id);
+            let gas = call.estimate_gas().await.map_err(SubmitError::not_sent)?;
+            call.gas(gas).send().await
+        }
+        _ => {
+            let (job_ids, results): (Vec<U256>, Vec<String>) = results.iter().cloned().unzip();
+            let call = contract
+                .callbackBatch(results, job_ids)
+                .nonce(nonce)
+                .max_fee_per_gas(fees.max_fee_per_gas)
+                .max_priority_fee_per_gas(fees.max_priority_fee_per_gas)
+                .from(evm_address)
+                .chain_id(chain_id);
+            let gas = call.estimate_gas().await.map_err(SubmitError::not_sent)?;
+            call.gas(gas).send().await
+        }
+    }
+    .map_err(|e| SubmitError {
+        reason: e.to_string(),
+        may_be_sent: true,
+    })?;
+    Ok((*pending_tx.tx_hash(), fees))
+}
+
+/// Returns the receipt of the transaction, or `None` if it has not been mined yet.
+pub async fn get_receipt(
+    chain_id: u64,
+    tx_hash: TxHash,
+) -> Result<Option<TransactionReceipt>, String> {
+    let rpc_service = read_state(|s| s.chain(chain_id).rpc_service.clone());
+    let provider = ProviderBuilder::new().on_icp(IcpConfig::new(rpc_service));
+    provider
+        .get_transaction_receipt(tx_hash)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Replays the result call of `results` on the state before the block `block_number`
+/// to find out why the result transaction that was mined in it reverted. The receipt of
+/// a reverted transaction does not contain the reason, and at `block_number` itself the
+/// call would run on the state after the transaction.
+pub async fn revert_reason(
+    chain_id: u64,
+    results: &[(U256, String)],
+    block_number: Option<u64>,
+) -> String {
+    let block = block_number.map_or(BlockId::latest(), |number| {
+        BlockId::number(number.saturating_sub(1))
     });
+    match replay_results(chain_id, results, block).await {
+        Ok(None) => "the transaction reverted, but the replayed call succeeded".to_string(),
+        Ok(Some(reason)) => reason,
+        Err(e) => format!("failed to replay the call: {}", e),
+    }
+}
 
-    // If no nonce exists, get it from the provider
-    let nonce = if let Some(nonce) = maybe_nonce {
-        nonce
-    } else {
-        provider
-            .get_transaction_count(evm_address)
-            .await
-            .unwrap_or(0)
-    };
+/// Replays the result call of `results` at `block` with `eth_call`. Returns the revert
+/// reason if the call reverts, or `None` if it succeeds.
+pub async fn replay_results(
+    chain_id: u64,
+    results: &[(U256, String)],
+    block: BlockId,
+) -> Result<Option<String>, String> {
+    let evm_address = read_state(|s| s.canister_evm_address)
+        .ok_or("the canister's EVM address is not initialized yet")?;
+    let rpc_service = read_state(|s| s.chain(chain_id).rpc_service.clone());
+    let provider = ProviderBuilder::new().on_icp(IcpConfig::new(rpc_service));
+    let contract_address = read_state(|s| s.chain(chain_id).coprocessor_evm_address);
+    let contract = Coprocessor::new(contract_address, provider);
 
-    match contract
-        .callback(result, job_id)
-        .nonce(nonce)
-        .from(evm_address)
-        .chain_id(chain_id)
-        .send()
-        .await
-    {
-        Ok(res) => {
-            let node_hash = *res.tx_hash();
-            let tx_response = contract
-                .provider()
-                .get_transaction_by_hash(node_hash)
+    let replayed = match results {
+        [(job_id, result)] => contract
+            .callback(result.clone(), *job_id)
+            .from(evm_address)
+            .block(block)
+            .call()
+            .await
+            .map(|_| ()),
+        _ => {
+            let (job_ids, results): (Vec<U256>, Vec<String>) = results.iter().cloned().unzip();
+            contract
+                .callbackBatch(results, job_ids)
+                .from(evm_address)
+                .block(block)
+                .call()
                 .await
-                .unwrap();
-
-            match tx_response {
-                Some(_tx) => {
-                    // The transaction has been mined and included in a block, the nonce
-                    // has been consumed. Save it to thread-local storage. Next transaction
-                    // for this address will use a nonce that is = this nonce + 1
-                    mutate_state(|s| {
-                        s.nonce = Some(nonce);
-                    });
-                    println!("Successfully ran job {}, tx: {}", job_id, res.tx_hash())
-                }
-                None => println!("{}", "Could not get transaction.".to_string()),
-            }
-        }
-        Err(e) => {
-            println!("{}", e.to_string())
+                .map(|_| ())
         }
+    };
+    match replayed {
+        Ok(()) => Ok(None),
+        Err(ContractError::TransportError(RpcError::ErrorResp(payload))) => Ok(Some(
+            payload
+                .as_revert_data()
+                .and_then(|data| decode_revert_reason(&data))
+                .unwrap_or_else(|| payload.message.to_string()),
+        )),
+        Err(e) => Err(e.to_string()),
     }
 }
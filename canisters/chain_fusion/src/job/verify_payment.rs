@@ -0,0 +1,85 @@
+use alloy::eips::BlockNumberOrTag;
+use alloy::primitives::U256;
+use alloy::providers::utils::{
+    eip1559_default_estimator, EIP1559_FEE_ESTIMATION_PAST_BLOCKS,
+    EIP1559_FEE_ESTIMATION_REWARD_PERCENTILE,
+};
+use alloy::providers::{Provider, ProviderBuilder};
+use alloy::transports::icp::IcpConfig;
+
+use super::handler::JobHandler;
+use crate::state::{read_state, Job, PaymentPolicy};
+
+/// What a job paid with the call that emitted its log and what the `PaymentPolicy`
+/// requires, both in wei.
+pub struct Payment {
+    pub paid: U256,
+    pub price: U256,
+}
+
+/// Decodes what the job paid from its log with its `handler`, or takes the value of its
+/// transaction if the log does not say, and prices the job at the max fee per gas on
+/// `result_chain_id`, where its result is posted.
+///
+/// The price is taken at the block of the job's log, which is what its requester
+/// could know when they paid, rather than at the time the job is verified. Results
+/// posted to another chain are priced at that chain's current fees, as the chains
+/// share no blocks. Both amounts are compared as wei, so the chains are expected to
+/// share their native currency.
+pub async fn verify_payment(
+    job: &Job,
+    handler: &dyn JobHandler,
+    result_chain_id: u64,
+    policy: &PaymentPolicy,
+) -> Result<Payment, String> {
+    // the value is emitted with the log rather than taken from the transaction, which
+    // may have called the contract through another one, except for events that don't
+    // emit it
+    let paid = match handler.payment(&job.log)? {
+        Some(paid) => paid,
+        None => transaction_value(job).await?,
+    };
+
+    let block = match job.log.block_number {
+        Some(block_number) if result_chain_id == job.chain_id => {
+            BlockNumberOrTag::Number(block_number)
+        }
+        _ => BlockNumberOrTag::Latest,
+    };
+    let rpc_service = read_state(|s| s.chain(result_chain_id).rpc_service.clone());
+    let provider = ProviderBuilder::new().on_icp(IcpConfig::new(rpc_service));
+    let fee_history = provider
+        .get_fee_history(
+            EIP1559_FEE_ESTIMATION_PAST_BLOCKS,
+            block,
+            &[EIP1559_FEE_ESTIMATION_REWARD_PERCENTILE],
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    let base_fee_per_gas = fee_history
+        .latest_block_base_fee()
+        .ok_or_else(|| format!("no base fee for block {}", block))?;
+    let fees = eip1559_default_estimator(base_fee_per_gas, &fee_history.reward.unwrap_or_default());
+
+    Ok(Payment {
+        paid,
+        price: policy.price(fees.max_fee_per_gas),
+    })
+}
+
+/// Fetches the value of the transaction that emitted the job's log, for events that
+/// do not emit what the job paid.
+async fn transaction_value(job: &Job) -> Result<U256, String> {
+    let tx_hash = job
+        .log
+        .transaction_hash
+        .ok_or("the log has no transaction hash")?;
+    let rpc_service = read_state(|s| s.chain(job.chain_id).rpc_service.clone());
+    let provider = ProviderBuilder::new().on_icp(IcpConfig::new(rpc_service));
+    let transaction = provider
+        .get_transaction_by_hash(tx_hash)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("transaction {} not found", tx_hash))?;
+    Ok(transaction.value)
+}
@@ -1,22 +1,48 @@
+mod admin;
+mod attestation;
+mod cycles;
+mod endpoints;
+mod gas;
 mod guard;
 mod job;
 mod lifecycle;
 mod logs;
+mod nonce;
 mod state;
-// uncomment to enable serving stored assets via http requests
-// mod storage;
+mod storage;
 
+use std::collections::BTreeSet;
 use std::time::Duration;
 
-use alloy::{network::TxSigner, signers::icp::IcpSigner, sol};
-use logs::scrape_eth_logs;
+use admin::{apply_config_change, describe_rpc_service, describe_scraping_backend};
+use alloy::{network::TxSigner, signers::icp::IcpSigner, sol, transports::icp::RpcService};
+use attestation::{AttestationInfo, AttestationKey};
+use candid::Nat;
+use job::{
+    monitor_transactions, schedule_batch_submission, start_jobs, MONITOR_TRANSACTIONS_INTERVAL,
+};
+use logs::{schedule_process_logs, schedule_scraping, schedule_scraping_in};
+use nonce::NonceStatus;
 
-use lifecycle::InitArg;
-use state::{read_state, State};
+use cycles::{check_cycles_balance, measure, CyclesSpent, CyclesUsage};
+use endpoints::{JobInfo, JobStatus};
+use gas::{check_gas_balances, GasBalanceStatus, CHECK_GAS_BALANCES_INTERVAL};
+use guard::{ActiveTask, RenewalGuard};
+use lifecycle::{
+    parse_address, validate_batch_policy, validate_event_signature, validate_max_jobs_in_flight,
+    validate_payment_policy, validate_scraping_backend, validate_scraping_cadence, CanisterArg,
+};
+use state::{
+    read_state, Alert, AuditLogEntry, BatchPolicy, PaymentPolicy, ResultSink, ScrapingBackend,
+    ScrapingCadence, State, TaskType,
+};
 
 use crate::state::{initialize_state, mutate_state};
 
+/// The default interval between two scrapes of a chain.
 pub const SCRAPING_LOGS_INTERVAL: Duration = Duration::from_secs(60);
+/// The default delay between installing or upgrading the canister and the first scrape.
+pub const INITIAL_SCRAPING_DELAY: Duration = Duration::from_secs(10);
 
 sol!(
     #[sol(rpc)]
@@ -35,21 +61,477 @@ fn setup_timers() {
             });
         })
     });
-    // // Start scraping logs almost immediately after the install, then repeat with the interval.
-    ic_cdk_timers::set_timer(Duration::from_secs(10), || ic_cdk::spawn(scrape_eth_logs()));
+    // Start scraping logs shortly after the install, then each chain is scraped
+    // according to its cadence.
+    let initial_delay = Duration::from_secs(read_state(|s| s.initial_scraping_delay_secs));
+    schedule_scraping_in(initial_delay);
+    ic_cdk_timers::set_timer_interval(MONITOR_TRANSACTIONS_INTERVAL, || {
+        ic_cdk::spawn(monitor_transactions())
+    });
+    ic_cdk_timers::set_timer_interval(CHECK_GAS_BALANCES_INTERVAL, || {
+        ic_cdk::spawn(check_gas_balances())
+    });
+    // results that waited for a batch before an upgrade
+    schedule_batch_submission();
 }
 
 #[ic_cdk::init]
-fn init(arg: InitArg) {
+fn init(arg: CanisterArg) {
+    let CanisterArg::Init(arg) = arg else {
+        ic_cdk::trap("expected an Init argument on install");
+    };
     initialize_state(state::State::try_from(arg).expect("BUG: failed to initialize canister"));
     setup_timers();
 }
 
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    read_state(storage::save_state);
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(arg: Option<CanisterArg>) {
+    let now = ic_cdk::api::time();
+    let (state, upgrade_arg) = match (storage::load_state(), arg) {
+        (Ok(Some(_)), Some(CanisterArg::Init(_))) => {
+            ic_cdk::trap("expected an Upgrade argument on upgrade")
+        }
+        (Ok(Some(state)), Some(CanisterArg::Upgrade(upgrade_arg))) => (state, upgrade_arg),
+        (Ok(Some(state)), None) => (state, None),
+        // the previous version did not save its state
+        (Ok(None), Some(CanisterArg::Init(arg))) => (
+            State::try_from(arg).expect("BUG: failed to initialize canister"),
+            None,
+        ),
+        (Ok(None), _) => ic_cdk::trap(
+            "the previous version did not save its state, upgrade with an Init argument instead",
+        ),
+        (Err(e), Some(CanisterArg::Init(arg))) => {
+            let mut state = State::try_from(arg).expect("BUG: failed to initialize canister");
+            state.record_alert(
+                format!("the saved state could not be loaded and was replaced: {e}"),
+                now,
+            );
+            (state, None)
+        }
+        (Err(e), _) => ic_cdk::trap(&format!(
+            "failed to load the saved state: {e}. Upgrade with an Init argument to replace it"
+        )),
+    };
+    initialize_state(state);
+    if let Some(arg) = upgrade_arg {
+        mutate_state(|s| s.upgrade(arg)).expect("BUG: failed to upgrade canister");
+    }
+    setup_timers();
+}
+
 #[ic_cdk::query]
 fn get_evm_address() -> Option<String> {
     read_state(|s| s.canister_evm_address.map(|x| x.to_string()))
 }
 
+/// Returns the jobs that have not reached a final status yet, at most
+/// `endpoints::MAX_JOBS_PER_PAGE` per call.
+#[ic_cdk::query]
+fn get_pending_jobs(offset: u64, limit: u64) -> Vec<JobInfo> {
+    read_state(|s| endpoints::paginate(s.logs_to_process.iter(), s, offset, limit))
+}
+
+/// Returns the jobs with a final status, i.e. confirmed, execution reverted, failed,
+/// compensated, unhandled, rejected and attested jobs, that are still retained,
+/// oldest first, at most `endpoints::MAX_JOBS_PER_PAGE` per call.
+#[ic_cdk::query]
+fn get_processed_jobs(offset: u64, limit: u64) -> Vec<JobInfo> {
+    storage::processed_jobs(
+        offset as usize,
+        (limit as usize).min(endpoints::MAX_JOBS_PER_PAGE),
+    )
+    .iter()
+    .map(|(source, job)| JobInfo::from_processed(source, job))
+    .collect()
+}
+
+#[ic_cdk::query]
+fn get_job(log_source: endpoints::LogSource) -> Result<Option<JobInfo>, String> {
+    let log_source = log_source.try_into()?;
+    Ok(read_state(|s| endpoints::get_job(s, &log_source)))
+}
+
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn get_dead_letter_jobs() -> Vec<JobInfo> {
+    read_state(|s| {
+        s.dead_letter_jobs
+            .iter()
+            .map(|(source, job)| JobInfo::new(source, job, s).with_status(JobStatus::DeadLetter))
+            .collect()
+    })
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn retry_dead_letter_job(log_source: endpoints::LogSource) -> Result<(), String> {
+    let log_source = log_source.try_into()?;
+    mutate_state(|s| s.retry_dead_letter_job(&log_source))
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn add_filter_address(chain_id: u64, address: String) -> Result<(), String> {
+    apply_config_change(
+        format!("add filter address {} on chain {}", address, chain_id),
+        |s| {
+            let address = parse_address(&address)?;
+            let chain = s.configured_chain_mut(chain_id)?;
+            if !chain.filter_addresses.contains(&address) {
+                chain.filter_addresses.push(address);
+            }
+            Ok(())
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn remove_filter_address(chain_id: u64, address: String) -> Result<(), String> {
+    apply_config_change(
+        format!("remove filter address {} on chain {}", address, chain_id),
+        |s| {
+            let address = parse_address(&address)?;
+            s.configured_chain_mut(chain_id)?
+                .filter_addresses
+                .retain(|a| *a != address);
+            Ok(())
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn add_filter_event(chain_id: u64, event: String) -> Result<(), String> {
+    apply_config_change(
+        format!("add filter event {} on chain {}", event, chain_id),
+        |s| {
+            validate_event_signature(&event)?;
+            let chain = s.configured_chain_mut(chain_id)?;
+            if !chain.filter_events.contains(&event) {
+                chain.filter_events.push(event);
+            }
+            Ok(())
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn remove_filter_event(chain_id: u64, event: String) -> Result<(), String> {
+    apply_config_change(
+        format!("remove filter event {} on chain {}", event, chain_id),
+        |s| {
+            s.configured_chain_mut(chain_id)?
+                .filter_events
+                .retain(|e| *e != event);
+            Ok(())
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_rpc_service(chain_id: u64, rpc_service: RpcService) -> Result<(), String> {
+    apply_config_change(
+        format!(
+            "set rpc service of chain {} to {}",
+            chain_id,
+            describe_rpc_service(&rpc_service)
+        ),
+        |s| {
+            s.configured_chain_mut(chain_id)?.rpc_service = rpc_service;
+            Ok(())
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_scraping_backend(chain_id: u64, scraping_backend: ScrapingBackend) -> Result<(), String> {
+    apply_config_change(
+        format!(
+            "set scraping backend of chain {} to {}",
+            chain_id,
+            describe_scraping_backend(&scraping_backend)
+        ),
+        |s| {
+            validate_scraping_backend(&scraping_backend)?;
+            s.configured_chain_mut(chain_id)?.scraping_backend = scraping_backend;
+            Ok(())
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_scraping_cadence(chain_id: u64, scraping_cadence: ScrapingCadence) -> Result<(), String> {
+    apply_config_change(
+        format!(
+            "set scraping cadence of chain {} to {:?}",
+            chain_id, scraping_cadence
+        ),
+        |s| {
+            let scraping_cadence = validate_scraping_cadence(scraping_cadence)?;
+            s.configured_chain_mut(chain_id)?
+                .set_scraping_cadence(scraping_cadence, ic_cdk::api::time());
+            Ok(())
+        },
+    )?;
+    schedule_scraping();
+    Ok(())
+}
+
+/// Sets how results reach the coprocessor contract on `chain_id`. Jobs that have
+/// already sent their result transaction keep it.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_result_sink(chain_id: u64, result_sink: ResultSink) -> Result<(), String> {
+    apply_config_change(
+        format!("set result sink of chain {} to {:?}", chain_id, result_sink),
+        |s| {
+            s.configured_chain_mut(chain_id)?.result_sink = result_sink;
+            Ok(())
+        },
+    )?;
+    start_jobs();
+    Ok(())
+}
+
+/// Returns the signed result of the job `job_id` for the coprocessor contract on
+/// `chain_id`, if its result chain uses `ResultSink::Attestation`. Anyone can relay
+/// it to the contract with `submitAttestedResult`.
+#[ic_cdk::query]
+fn get_attestation(chain_id: u64, job_id: Nat) -> Result<Option<AttestationInfo>, String> {
+    let key = AttestationKey {
+        chain_id,
+        job_id: endpoints::from_nat(&job_id)?,
+    };
+    Ok(storage::get_attestation(&key).map(AttestationInfo::from))
+}
+
+/// Signs the result of the job `job_id` for the coprocessor contract on `chain_id`
+/// again once its attestation has expired, see `attestation::ATTESTATION_VALIDITY`.
+/// Controllers can renew any attestation, anyone else has to prove to be the
+/// requester of the job with `requester_signature`, see
+/// `attestation::verify_requester`.
+#[ic_cdk::update]
+async fn renew_attestation(
+    chain_id: u64,
+    job_id: Nat,
+    requester_signature: Option<String>,
+) -> Result<AttestationInfo, String> {
+    if !check_cycles_balance() {
+        return Err("the cycles balance is below the minimum".to_string());
+    }
+    let key = AttestationKey {
+        chain_id,
+        job_id: endpoints::from_nat(&job_id)?,
+    };
+    let attestation =
+        storage::get_attestation(&key).ok_or("there is no attestation for the job")?;
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        let signature = requester_signature
+            .ok_or("only controllers and the requester of the job can renew its attestation")?;
+        attestation::verify_requester(&attestation, &signature)?;
+    }
+    let _guard = RenewalGuard::new(key)?;
+    let (renewed, cycles) = measure(attestation::renew(attestation)).await;
+    mutate_state(|s| s.cycles_spent += CyclesSpent::transaction(cycles, renewed.is_ok()));
+    let renewed = renewed?;
+    // the job may have been reverted by a reorg while it was signed
+    if storage::get_attestation(&key).is_none() {
+        return Err("the job was reverted".to_string());
+    }
+    storage::record_attestation(renewed.clone());
+    Ok(AttestationInfo::from(renewed))
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_max_jobs_in_flight(max_jobs_in_flight: u32) -> Result<(), String> {
+    apply_config_change(
+        format!("set max jobs in flight to {}", max_jobs_in_flight),
+        |s| {
+            s.max_jobs_in_flight = validate_max_jobs_in_flight(max_jobs_in_flight)?;
+            Ok(())
+        },
+    )?;
+    start_jobs();
+    Ok(())
+}
+
+/// Sets what jobs have to pay with the transaction that created them, or stops
+/// verifying payments if `payment_policy` is `None`.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_payment_policy(payment_policy: Option<PaymentPolicy>) -> Result<(), String> {
+    apply_config_change(format!("set payment policy to {:?}", payment_policy), |s| {
+        s.payment_policy = payment_policy.map(validate_payment_policy).transpose()?;
+        Ok(())
+    })
+}
+
+/// Sets how results are collected into batches, or goes back to sending every result
+/// in a transaction of its own if `batch_policy` is `None`.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_batch_policy(batch_policy: Option<BatchPolicy>) -> Result<(), String> {
+    apply_config_change(format!("set batch policy to {:?}", batch_policy), |s| {
+        s.batch_policy = batch_policy.map(validate_batch_policy).transpose()?;
+        Ok(())
+    })?;
+    // results that waited for a batch are submitted one by one without a policy
+    start_jobs();
+    schedule_batch_submission();
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn pause_scraping() -> Result<(), String> {
+    apply_config_change("pause scraping".to_string(), |s| {
+        s.scraping_paused = true;
+        Ok(())
+    })
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn resume_scraping() -> Result<(), String> {
+    apply_config_change("resume scraping".to_string(), |s| {
+        s.scraping_paused = false;
+        Ok(())
+    })
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn pause_processing() -> Result<(), String> {
+    apply_config_change("pause processing".to_string(), |s| {
+        s.processing_paused = true;
+        Ok(())
+    })
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn resume_processing() -> Result<(), String> {
+    apply_config_change("resume processing".to_string(), |s| {
+        s.processing_paused = false;
+        Ok(())
+    })?;
+    start_jobs();
+    schedule_batch_submission();
+    Ok(())
+}
+
+/// Returns the canister's cycles balance and the cycles it spent on scraping, signing
+/// and RPC calls. The cycles spent on each job are part of its `JobInfo`.
+#[ic_cdk::query]
+fn get_cycles_usage() -> CyclesUsage {
+    read_state(|s| CyclesUsage {
+        balance: ic_cdk::api::canister_balance128(),
+        min_cycles_balance: s.min_cycles_balance,
+        spent: s.cycles_spent,
+    })
+}
+
+/// Returns the balance of the canister's EVM address on every chain that results are
+/// sent to with result transactions, as of the last check. Jobs stay queued while it
+/// does not cover the next result transaction.
+#[ic_cdk::query]
+fn get_gas_balances() -> Vec<GasBalanceStatus> {
+    read_state(|s| {
+        let result_chain_ids: BTreeSet<u64> = s
+            .chains
+            .values()
+            .map(|chain| chain.result_chain_id())
+            .filter(|chain_id| s.chain(*chain_id).result_sink == ResultSink::Transaction)
+            .collect();
+        result_chain_ids
+            .into_iter()
+            .map(|chain_id| GasBalanceStatus::new(s.chain(chain_id), s))
+            .collect()
+    })
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_min_cycles_balance(min_cycles_balance: Option<u128>) -> Result<(), String> {
+    apply_config_change(
+        format!("set min cycles balance to {:?}", min_cycles_balance),
+        |s| {
+            s.min_cycles_balance = min_cycles_balance;
+            Ok(())
+        },
+    )
+}
+
+/// Returns the most recent alerts, e.g. about providers that keep returning
+/// inconsistent logs or a cycles balance below the minimum.
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn get_alerts() -> Vec<Alert> {
+    read_state(|s| s.alerts.clone())
+}
+
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn get_audit_log() -> Vec<AuditLogEntry> {
+    read_state(|s| s.audit_log.clone())
+}
+
+/// Returns the next nonce of the canister's EVM address and the transactions that
+/// have not been mined yet, for each chain.
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn get_nonce_status() -> Vec<NonceStatus> {
+    read_state(|s| {
+        s.chains
+            .iter()
+            .map(|(chain_id, chain)| NonceStatus::new(*chain_id, &chain.nonces))
+            .collect()
+    })
+}
+
+/// Returns the tasks that are currently running and when they acquired their guard.
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn get_active_tasks() -> Vec<ActiveTask> {
+    read_state(|s| {
+        s.active_tasks
+            .iter()
+            .map(|(task, acquired_at)| ActiveTask {
+                task: *task,
+                acquired_at: *acquired_at,
+            })
+            .collect()
+    })
+}
+
+/// Releases the guards of tasks that have been running for longer than
+/// `max_age_secs` (by default the lease of each task, see `guard::lease`), e.g.
+/// because they trapped after an `await`, re-arms the timers of the released tasks
+/// and returns them.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn clear_stale_guards(max_age_secs: Option<u64>) -> Result<Vec<TaskType>, String> {
+    let max_age = max_age_secs.map(Duration::from_secs);
+    let mut cleared_tasks = vec![];
+    apply_config_change(
+        match max_age {
+            Some(max_age) => format!("clear guards older than {} seconds", max_age.as_secs()),
+            None => "clear guards older than their lease".to_string(),
+        },
+        |s| {
+            cleared_tasks = guard::clear_stale_guards(s, max_age, ic_cdk::api::time());
+            Ok(())
+        },
+    )?;
+    for task in cleared_tasks.iter() {
+        match task {
+            TaskType::ScrapeLogs => schedule_scraping(),
+            TaskType::ProcessLogs => schedule_process_logs(),
+            TaskType::SubmitBatches => schedule_batch_submission(),
+            // these run on interval timers that are still armed
+            TaskType::MonitorTransactions | TaskType::CheckGasBalances => {}
+        }
+    }
+    Ok(cleared_tasks)
+}
+
+fn caller_is_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err("only controllers can call this method".to_string())
+    }
+}
+
 // uncomment this if you need to serve stored assets from `storage.rs` via http requests
 
 // #[ic_cdk::query]
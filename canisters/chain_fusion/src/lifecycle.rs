@@ -1,8 +1,16 @@
-use crate::state::{InvalidStateError, State};
+use crate::logs::MAX_BLOCK_RANGE;
+use crate::state::{
+    BatchPolicy, ChainState, ConfirmationPolicy, InvalidStateError, PaymentPolicy, ReorgPolicy,
+    ResubmissionPolicy, ResultSink, RetentionPolicy, RetryPolicy, ScrapingBackend, ScrapingCadence,
+    State, DEFAULT_MAX_JOBS_IN_FLIGHT, MIN_FEE_BUMP_PERCENT,
+};
+use crate::INITIAL_SCRAPING_DELAY;
 use alloy::primitives::Address;
 use alloy::transports::icp::RpcService;
 use candid::{CandidType, Deserialize};
+use evm_rpc_canister_types::{ConsensusStrategy, RpcServices};
 use ic_cdk::api::management_canister::ecdsa::EcdsaKeyId;
+use std::collections::BTreeMap;
 use std::str::FromStr;
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
@@ -13,49 +21,596 @@ pub struct InitArg {
     pub coprocessor_evm_address: String,
     pub filter_events: Vec<String>,
     pub ecdsa_key_id: EcdsaKeyId,
+    /// The block to start scraping logs from. Defaults to the latest block at the
+    /// time of the first scrape.
+    pub start_block: Option<u64>,
+    /// Up to which block logs are scraped. Defaults to the latest block.
+    pub confirmation_policy: Option<ConfirmationPolicy>,
+    /// Whether processed jobs that are re-emitted after a reorg are run again or
+    /// compensated. Defaults to running them again.
+    pub reorg_policy: Option<ReorgPolicy>,
+    /// How failed jobs are retried. Defaults to 5 attempts with a backoff starting at
+    /// one minute.
+    pub retry_policy: Option<RetryPolicy>,
+    /// How many processed jobs are kept. Defaults to the last 10,000 jobs.
+    pub retention_policy: Option<RetentionPolicy>,
+    /// When result transactions that are not mined are replaced. Defaults to a 10%
+    /// fee bump after five minutes.
+    pub resubmission_policy: Option<ResubmissionPolicy>,
+    /// Where logs are scraped from. Defaults to `rpc_service`.
+    pub scraping_backend: Option<ScrapingBackend>,
+    /// How often logs are scraped. Defaults to every 60 seconds.
+    pub scraping_cadence: Option<ScrapingCadence>,
+    /// The delay before the first scrape after an install or upgrade. Defaults to 10
+    /// seconds.
+    pub initial_scraping_delay_secs: Option<u64>,
+    /// How many jobs are run concurrently. Defaults to 10.
+    pub max_jobs_in_flight: Option<u32>,
+    /// The cycles balance below which processing is paused. Defaults to no minimum.
+    pub min_cycles_balance: Option<u128>,
+    /// What jobs have to pay with the transaction that created them. Defaults to not
+    /// verifying payments.
+    pub payment_policy: Option<PaymentPolicy>,
+    /// How results are collected into batches. Defaults to sending every result in a
+    /// transaction of its own.
+    pub batch_policy: Option<BatchPolicy>,
+    /// The chain that the results of jobs from `chain_id` are posted to. Defaults to
+    /// `chain_id` itself.
+    pub result_chain_id: Option<u64>,
+    /// How results reach the coprocessor contract on `chain_id`. Defaults to result
+    /// transactions.
+    pub result_sink: Option<ResultSink>,
+    /// Further chains to scrape logs from, next to `chain_id`.
+    pub chains: Option<Vec<ChainArg>>,
 }
 
-impl TryFrom<InitArg> for State {
+/// The configuration of an additional chain.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ChainArg {
+    pub rpc_service: RpcService,
+    pub chain_id: u64,
+    pub filter_addresses: Vec<String>,
+    pub coprocessor_evm_address: String,
+    pub filter_events: Vec<String>,
+    pub start_block: Option<u64>,
+    pub confirmation_policy: Option<ConfirmationPolicy>,
+    pub scraping_backend: Option<ScrapingBackend>,
+    pub scraping_cadence: Option<ScrapingCadence>,
+    pub result_chain_id: Option<u64>,
+    pub result_sink: Option<ResultSink>,
+}
+
+/// Partial reconfiguration applied in `post_upgrade`. Fields that are `None` keep
+/// their current value.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct UpgradeArg {
+    /// The chain that the chain specific fields below apply to. May be omitted if
+    /// only one chain is configured. Before several chains could be watched it set
+    /// the chain id, now it only selects a configured chain, so an unknown chain id is
+    /// rejected instead of changing it.
+    pub chain_id: Option<u64>,
+    pub rpc_service: Option<RpcService>,
+    pub filter_addresses: Option<Vec<String>>,
+    pub coprocessor_evm_address: Option<String>,
+    pub filter_events: Option<Vec<String>>,
+    pub confirmation_policy: Option<ConfirmationPolicy>,
+    pub reorg_policy: Option<ReorgPolicy>,
+    pub retry_policy: Option<RetryPolicy>,
+    pub retention_policy: Option<RetentionPolicy>,
+    pub resubmission_policy: Option<ResubmissionPolicy>,
+    pub scraping_backend: Option<ScrapingBackend>,
+    pub scraping_cadence: Option<ScrapingCadence>,
+    pub initial_scraping_delay_secs: Option<u64>,
+    pub max_jobs_in_flight: Option<u32>,
+    pub min_cycles_balance: Option<u128>,
+    pub payment_policy: Option<PaymentPolicy>,
+    pub batch_policy: Option<BatchPolicy>,
+    pub result_chain_id: Option<u64>,
+    pub result_sink: Option<ResultSink>,
+    /// Chains to add. Chains that are already configured are rejected.
+    pub chains: Option<Vec<ChainArg>>,
+}
+
+/// The argument of the canister: an `InitArg` on install and an optional
+/// `UpgradeArg` on upgrade. A single type is needed because Candid only describes one
+/// argument for both.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum CanisterArg {
+    Init(InitArg),
+    Upgrade(Option<UpgradeArg>),
+}
+
+pub fn parse_address(address: &str) -> Result<Address, InvalidStateError> {
+    Address::from_str(address)
+        .map_err(|e| InvalidStateError::InvalidEthereumContractAddress(format!("ERROR: {}", e)))
+}
+
+/// Checks that `signature` has the form `Name(type1,type2,...)`, as expected by
+/// `Filter::events`.
+pub fn validate_event_signature(signature: &str) -> Result<(), InvalidStateError> {
+    let is_valid = match signature.split_once('(') {
+        Some((name, params)) => {
+            !name.is_empty()
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                && params.ends_with(')')
+                && !params.contains(char::is_whitespace)
+        }
+        None => false,
+    };
+    if !is_valid {
+        return Err(InvalidStateError::InvalidEventSignature(format!(
+            "ERROR: {} is not of the form Name(type1,type2,...)",
+            signature
+        )));
+    }
+    Ok(())
+}
+
+fn validate_scraping_interval(interval_secs: u64) -> Result<(), InvalidStateError> {
+    if interval_secs == 0 {
+        return Err(InvalidStateError::InvalidScrapingInterval(
+            "ERROR: the scraping interval must be at least 1 second".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn validate_scraping_cadence(
+    cadence: ScrapingCadence,
+) -> Result<ScrapingCadence, InvalidStateError> {
+    match cadence {
+        ScrapingCadence::Fixed { interval_secs } => validate_scraping_interval(interval_secs)?,
+        ScrapingCadence::Adaptive {
+            min_interval_secs,
+            max_interval_secs,
+        } => {
+            validate_scraping_interval(min_interval_secs)?;
+            if min_interval_secs > max_interval_secs {
+                return Err(InvalidStateError::InvalidScrapingInterval(
+                    "ERROR: min_interval_secs must not exceed max_interval_secs".to_string(),
+                ));
+            }
+        }
+    }
+    Ok(cadence)
+}
+
+pub fn validate_max_jobs_in_flight(max_jobs_in_flight: u32) -> Result<u32, InvalidStateError> {
+    if max_jobs_in_flight == 0 {
+        return Err(InvalidStateError::InvalidMaxJobsInFlight(
+            "ERROR: at least one job must be run at a time".to_string(),
+        ));
+    }
+    Ok(max_jobs_in_flight)
+}
+
+pub fn validate_batch_policy(batch_policy: BatchPolicy) -> Result<BatchPolicy, InvalidStateError> {
+    if batch_policy.max_batch_size < 2 {
+        return Err(InvalidStateError::InvalidBatchPolicy(
+            "ERROR: max_batch_size must be at least 2".to_string(),
+        ));
+    }
+    if batch_policy.window_secs == 0 {
+        return Err(InvalidStateError::InvalidBatchPolicy(
+            "ERROR: window_secs must be greater than 0".to_string(),
+        ));
+    }
+    Ok(batch_policy)
+}
+
+pub fn validate_payment_policy(
+    payment_policy: PaymentPolicy,
+) -> Result<PaymentPolicy, InvalidStateError> {
+    if payment_policy.gas_per_job == 0 {
+        return Err(InvalidStateError::InvalidPaymentPolicy(
+            "ERROR: gas_per_job must be greater than 0".to_string(),
+        ));
+    }
+    Ok(payment_policy)
+}
+
+fn validate_retry_policy(retry_policy: RetryPolicy) -> Result<RetryPolicy, InvalidStateError> {
+    if retry_policy.max_attempts == 0 {
+        return Err(InvalidStateError::InvalidRetryPolicy(
+            "ERROR: max_attempts must be at least 1".to_string(),
+        ));
+    }
+    if retry_policy.initial_backoff_secs > retry_policy.max_backoff_secs {
+        return Err(InvalidStateError::InvalidRetryPolicy(
+            "ERROR: initial_backoff_secs must not exceed max_backoff_secs".to_string(),
+        ));
+    }
+    Ok(retry_policy)
+}
+
+fn validate_retention_policy(
+    retention_policy: RetentionPolicy,
+) -> Result<RetentionPolicy, InvalidStateError> {
+    if retention_policy.max_entries == Some(0) || retention_policy.max_age_secs == Some(0) {
+        return Err(InvalidStateError::InvalidRetentionPolicy(
+            "ERROR: processed jobs must be retained for deduplication".to_string(),
+        ));
+    }
+    Ok(retention_policy)
+}
+
+fn validate_resubmission_policy(
+    resubmission_policy: ResubmissionPolicy,
+) -> Result<ResubmissionPolicy, InvalidStateError> {
+    if resubmission_policy.timeout_secs == 0 {
+        return Err(InvalidStateError::InvalidResubmissionPolicy(
+            "ERROR: timeout_secs must be greater than 0".to_string(),
+        ));
+    }
+    if resubmission_policy.fee_bump_percent < MIN_FEE_BUMP_PERCENT {
+        return Err(InvalidStateError::InvalidResubmissionPolicy(format!(
+            "ERROR: fee_bump_percent must be at least {}",
+            MIN_FEE_BUMP_PERCENT
+        )));
+    }
+    Ok(resubmission_policy)
+}
+
+pub fn validate_scraping_backend(backend: &ScrapingBackend) -> Result<(), InvalidStateError> {
+    let ScrapingBackend::EvmRpc(config) = backend else {
+        return Ok(());
+    };
+    let providers = match &config.rpc_services {
+        RpcServices::Custom { services, .. } => Some(services.len()),
+        RpcServices::EthMainnet(services) => services.as_ref().map(Vec::len),
+        RpcServices::EthSepolia(services) => services.as_ref().map(Vec::len),
+        RpcServices::ArbitrumOne(services)
+        | RpcServices::BaseMainnet(services)
+        | RpcServices::OptimismMainnet(services) => services.as_ref().map(Vec::len),
+    };
+    if providers == Some(0) {
+        return Err(InvalidStateError::InvalidScrapingBackend(
+            "ERROR: at least one RPC service is required".to_string(),
+        ));
+    }
+    if let ConsensusStrategy::Threshold { min, total } = config.consensus {
+        if min == 0 || total.is_some_and(|total| total < min) {
+            return Err(InvalidStateError::InvalidScrapingBackend(format!(
+                "ERROR: invalid consensus threshold {} out of {:?}",
+                min, total
+            )));
+        }
+    }
+    Ok(())
+}
+
+impl TryFrom<ChainArg> for ChainState {
     type Error = InvalidStateError;
 
     fn try_from(
-        InitArg {
+        ChainArg {
             rpc_service,
             chain_id,
             filter_addresses,
-            filter_events,
             coprocessor_evm_address,
-            ecdsa_key_id,
-        }: InitArg,
+            filter_events,
+            start_block,
+            confirmation_policy,
+            scraping_backend,
+            scraping_cadence,
+            result_chain_id,
+            result_sink,
+        }: ChainArg,
     ) -> Result<Self, Self::Error> {
         let validated_filter_addresses: Vec<Address> = filter_addresses
             .iter()
-            .map(|address| {
-                Address::from_str(address).map_err(|e| {
-                    InvalidStateError::InvalidEthereumContractAddress(format!("ERROR: {}", e))
-                })
-            })
+            .map(|address| parse_address(address))
             .collect::<Result<_, _>>()?;
+        let validated_coprocessor_evm_address = parse_address(&coprocessor_evm_address)?;
+        for event in filter_events.iter() {
+            validate_event_signature(event)?;
+        }
+        let scraping_backend = scraping_backend.unwrap_or_default();
+        validate_scraping_backend(&scraping_backend)?;
+        let validated_scraping_cadence =
+            validate_scraping_cadence(scraping_cadence.unwrap_or_default())?;
 
-        let validated_coprocessor_evm_address = Address::from_str(&coprocessor_evm_address)
-            .map_err(|e| {
-                InvalidStateError::InvalidEthereumContractAddress(format!("ERROR: {}", e))
-            })?;
-
-        let state = Self {
+        Ok(Self {
             rpc_service,
+            scraping_backend,
             chain_id,
+            coprocessor_evm_address: validated_coprocessor_evm_address,
+            result_chain_id,
             filter_addresses: validated_filter_addresses,
             filter_events,
-            coprocessor_evm_address: validated_coprocessor_evm_address,
+            nonces: Default::default(),
+            start_block,
+            last_scraped_block: None,
+            confirmation_policy: confirmation_policy.unwrap_or_default(),
+            block_range: MAX_BLOCK_RANGE,
+            scraped_block_hashes: Default::default(),
+            scraping_cadence: validated_scraping_cadence,
+            scraping_interval_secs: validated_scraping_cadence.initial_interval_secs(),
+            next_scrape_at: 0,
+            inconsistent_scrapes: 0,
+            gas_balance: None,
+            last_callback_gas_used: None,
+            result_sink: result_sink.unwrap_or_default(),
+        })
+    }
+}
+
+/// Adds the validated `chains` to `existing`, rejecting duplicate chain ids.
+fn add_chains(
+    existing: &mut BTreeMap<u64, ChainState>,
+    chains: Vec<ChainArg>,
+) -> Result<(), InvalidStateError> {
+    for chain in chains {
+        let chain = ChainState::try_from(chain)?;
+        if existing.contains_key(&chain.chain_id) {
+            return Err(InvalidStateError::InvalidChain(format!(
+                "ERROR: chain {} is configured twice",
+                chain.chain_id
+            )));
+        }
+        existing.insert(chain.chain_id, chain);
+    }
+    Ok(())
+}
+
+/// Checks that the results of every chain are posted to a configured chain.
+fn validate_result_chains(chains: &BTreeMap<u64, ChainState>) -> Result<(), InvalidStateError> {
+    for chain in chains.values() {
+        if !chains.contains_key(&chain.result_chain_id()) {
+            return Err(InvalidStateError::InvalidChain(format!(
+                "ERROR: the results of chain {} are posted to the unknown chain {}",
+                chain.chain_id,
+                chain.result_chain_id()
+            )));
+        }
+    }
+    Ok(())
+}
+
+impl TryFrom<InitArg> for State {
+    type Error = InvalidStateError;
+
+    fn try_from(
+        InitArg {
+            rpc_service,
+            chain_id,
+            filter_addresses,
+            filter_events,
+            coprocessor_evm_address,
+            ecdsa_key_id,
+            start_block,
+            confirmation_policy,
+            reorg_policy,
+            retry_policy,
+            retention_policy,
+            resubmission_policy,
+            scraping_backend,
+            scraping_cadence,
+            initial_scraping_delay_secs,
+            max_jobs_in_flight,
+            min_cycles_balance,
+            payment_policy,
+            batch_policy,
+            result_chain_id,
+            result_sink,
+            chains,
+        }: InitArg,
+    ) -> Result<Self, Self::Error> {
+        let mut validated_chains = BTreeMap::new();
+        let primary_chain = ChainArg {
+            rpc_service,
+            chain_id,
+            filter_addresses,
+            coprocessor_evm_address,
+            filter_events,
+            start_block,
+            confirmation_policy,
+            scraping_backend,
+            scraping_cadence,
+            result_chain_id,
+            result_sink,
+        };
+        add_chains(
+            &mut validated_chains,
+            std::iter::once(primary_chain)
+                .chain(chains.unwrap_or_default())
+                .collect(),
+        )?;
+        validate_result_chains(&validated_chains)?;
+        let validated_retry_policy = validate_retry_policy(retry_policy.unwrap_or_default())?;
+        let validated_retention_policy =
+            validate_retention_policy(retention_policy.unwrap_or_default())?;
+        let validated_resubmission_policy =
+            validate_resubmission_policy(resubmission_policy.unwrap_or_default())?;
+        let validated_max_jobs_in_flight =
+            validate_max_jobs_in_flight(max_jobs_in_flight.unwrap_or(DEFAULT_MAX_JOBS_IN_FLIGHT))?;
+        let validated_payment_policy = payment_policy.map(validate_payment_policy).transpose()?;
+        let validated_batch_policy = batch_policy.map(validate_batch_policy).transpose()?;
+
+        let state = Self {
+            chains: validated_chains,
             logs_to_process: Default::default(),
-            processed_logs: Default::default(),
+            dead_letter_jobs: Default::default(),
+            retry_policy: validated_retry_policy,
+            resubmission_policy: validated_resubmission_policy,
+            reverted_logs: Default::default(),
+            logs_to_compensate: Default::default(),
+            retention_policy: validated_retention_policy,
             active_tasks: Default::default(),
+            max_jobs_in_flight: validated_max_jobs_in_flight,
+            jobs_in_flight: Default::default(),
+            renewals_in_progress: Default::default(),
             signer: None,
             ecdsa_key_id,
             canister_evm_address: None,
-            nonce: None,
+            reorg_policy: reorg_policy.unwrap_or_default(),
+            initial_scraping_delay_secs: initial_scraping_delay_secs
+                .unwrap_or(INITIAL_SCRAPING_DELAY.as_secs()),
+            scraping_timer: None,
+            scraping_paused: false,
+            processing_paused: false,
+            audit_log: Default::default(),
+            alerts: Default::default(),
+            cycles_spent: Default::default(),
+            min_cycles_balance,
+            payment_policy: validated_payment_policy,
+            batch_policy: validated_batch_policy,
+            batch_timer: None,
         };
         Ok(state)
     }
 }
+
+impl State {
+    /// Applies the given upgrade argument. All fields are validated before any of
+    /// them is applied, so an invalid argument leaves the state untouched.
+    pub fn upgrade(
+        &mut self,
+        UpgradeArg {
+            chain_id,
+            rpc_service,
+            filter_addresses,
+            coprocessor_evm_address,
+            filter_events,
+            confirmation_policy,
+            reorg_policy,
+            retry_policy,
+            retention_policy,
+            resubmission_policy,
+            scraping_backend,
+            scraping_cadence,
+            initial_scraping_delay_secs,
+            max_jobs_in_flight,
+            min_cycles_balance,
+            payment_policy,
+            batch_policy,
+            result_chain_id,
+            result_sink,
+            chains,
+        }: UpgradeArg,
+    ) -> Result<(), InvalidStateError> {
+        let validated_filter_addresses: Option<Vec<Address>> = filter_addresses
+            .map(|addresses| {
+                addresses
+                    .iter()
+                    .map(|address| parse_address(address))
+                    .collect::<Result<_, _>>()
+            })
+            .transpose()?;
+        let validated_coprocessor_evm_address = coprocessor_evm_address
+            .map(|address| parse_address(&address))
+            .transpose()?;
+        let validated_retry_policy = retry_policy.map(validate_retry_policy).transpose()?;
+        let validated_retention_policy = retention_policy
+            .map(validate_retention_policy)
+            .transpose()?;
+        let validated_resubmission_policy = resubmission_policy
+            .map(validate_resubmission_policy)
+            .transpose()?;
+        if let Some(scraping_backend) = &scraping_backend {
+            validate_scraping_backend(scraping_backend)?;
+        }
+        let validated_scraping_cadence = scraping_cadence
+            .map(validate_scraping_cadence)
+            .transpose()?;
+        let validated_max_jobs_in_flight = max_jobs_in_flight
+            .map(validate_max_jobs_in_flight)
+            .transpose()?;
+        let validated_payment_policy = payment_policy.map(validate_payment_policy).transpose()?;
+        let validated_batch_policy = batch_policy.map(validate_batch_policy).transpose()?;
+        for event in filter_events.iter().flatten() {
+            validate_event_signature(event)?;
+        }
+        let mut validated_chains = self.chains.clone();
+        add_chains(&mut validated_chains, chains.unwrap_or_default())?;
+
+        let has_chain_fields = rpc_service.is_some()
+            || validated_filter_addresses.is_some()
+            || validated_coprocessor_evm_address.is_some()
+            || filter_events.is_some()
+            || confirmation_policy.is_some()
+            || scraping_backend.is_some()
+            || validated_scraping_cadence.is_some()
+            || result_chain_id.is_some()
+            || result_sink.is_some();
+        if has_chain_fields {
+            let chain_id = match chain_id {
+                Some(chain_id) => chain_id,
+                None if self.chains.len() == 1 => *self
+                    .chains
+                    .keys()
+                    .next()
+                    .expect("BUG: one chain is configured"),
+                None => {
+                    return Err(InvalidStateError::InvalidChain(
+                        "ERROR: chain_id is required if several chains are configured".to_string(),
+                    ))
+                }
+            };
+            let chain = validated_chains.get_mut(&chain_id).ok_or_else(|| {
+                InvalidStateError::InvalidChain(format!(
+                    "ERROR: unknown chain {}: chain_id selects a configured chain, new chains are added with chains",
+                    chain_id
+                ))
+            })?;
+            if let Some(rpc_service) = rpc_service {
+                chain.rpc_service = rpc_service;
+            }
+            if let Some(filter_addresses) = validated_filter_addresses {
+                chain.filter_addresses = filter_addresses;
+            }
+            if let Some(coprocessor_evm_address) = validated_coprocessor_evm_address {
+                chain.coprocessor_evm_address = coprocessor_evm_address;
+            }
+            if let Some(filter_events) = filter_events {
+                chain.filter_events = filter_events;
+            }
+            if let Some(confirmation_policy) = confirmation_policy {
+                chain.confirmation_policy = confirmation_policy;
+            }
+            if let Some(scraping_backend) = scraping_backend {
+                chain.scraping_backend = scraping_backend;
+            }
+            if let Some(scraping_cadence) = validated_scraping_cadence {
+                chain.set_scraping_cadence(scraping_cadence, ic_cdk::api::time());
+            }
+            if let Some(result_chain_id) = result_chain_id {
+                chain.result_chain_id = Some(result_chain_id);
+            }
+            if let Some(result_sink) = result_sink {
+                chain.result_sink = result_sink;
+            }
+        }
+        validate_result_chains(&validated_chains)?;
+
+        self.chains = validated_chains;
+        if let Some(reorg_policy) = reorg_policy {
+            self.reorg_policy = reorg_policy;
+        }
+        if let Some(initial_scraping_delay_secs) = initial_scraping_delay_secs {
+            self.initial_scraping_delay_secs = initial_scraping_delay_secs;
+        }
+        if let Some(max_jobs_in_flight) = validated_max_jobs_in_flight {
+            self.max_jobs_in_flight = max_jobs_in_flight;
+        }
+        if let Some(min_cycles_balance) = min_cycles_balance {
+            self.min_cycles_balance = Some(min_cycles_balance);
+        }
+        if let Some(payment_policy) = validated_payment_policy {
+            self.payment_policy = Some(payment_policy);
+        }
+        if let Some(batch_policy) = validated_batch_policy {
+            self.batch_policy = Some(batch_policy);
+        }
+        if let Some(retry_policy) = validated_retry_policy {
+            self.retry_policy = retry_policy;
+        }
+        if let Some(retention_policy) = validated_retention_policy {
+            self.retention_policy = retention_policy;
+        }
+        if let Some(resubmission_policy) = validated_resubmission_policy {
+            self.resubmission_policy = resubmission_policy;
+        }
+        Ok(())
+    }
+}
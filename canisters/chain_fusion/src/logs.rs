@@ -1,65 +1,557 @@
+mod evm_rpc;
+
 use std::time::Duration;
 
-use crate::SCRAPING_LOGS_INTERVAL;
 use crate::{
+    cycles::{check_cycles_balance, measure, CyclesSpent},
     guard::TimerGuard,
-    job::job,
-    state::{mutate_state, read_state, State, TaskType},
+    job::{compensate, start_jobs},
+    nonce::reconcile_nonces,
+    state::{
+        mutate_state, read_state, ConfirmationPolicy, IntoLogSource, ScrapingBackend, State,
+        TaskType,
+    },
 };
-use alloy::rpc::types::Filter;
-use alloy::{eips::BlockNumberOrTag, providers::Provider};
-use alloy::{providers::ProviderBuilder, rpc::types::Log, transports::icp::IcpConfig};
+use alloy::eips::BlockNumberOrTag;
+use alloy::primitives::{B256, U64};
+use alloy::providers::Provider;
+use alloy::rpc::types::{Filter, Log};
+use alloy::transports::{Transport, TransportErrorKind, TransportResult};
+use alloy::{providers::ProviderBuilder, transports::icp::IcpConfig};
+use candid::Deserialize;
+use ic_cdk::println;
+
+/// The maximum size of a single `eth_getLogs` response in bytes.
+const MAX_RESPONSE_SIZE: u64 = 100_000;
+/// The maximum size of the response for a single block whose logs do not fit into
+/// `MAX_RESPONSE_SIZE`, the largest response of an HTTPS outcall.
+const MAX_SINGLE_BLOCK_RESPONSE_SIZE: u64 = 2_000_000;
+/// The largest number of blocks requested in a single `eth_getLogs` call.
+pub const MAX_BLOCK_RANGE: u64 = 500;
+/// The number of scrapes in a row whose providers returned inconsistent logs after
+/// which an alert is raised.
+const MAX_INCONSISTENT_SCRAPES: u32 = 3;
 
 async fn process_logs() {
+    if read_state(|s| s.processing_paused) {
+        return;
+    }
     let _guard = match TimerGuard::new(TaskType::ProcessLogs) {
         Ok(guard) => guard,
         Err(_) => return,
     };
 
-    let logs_to_process = read_state(|s| (s.logs_to_process.clone()));
+    // Catch up with transactions that were mined or dropped since the last run and
+    // fill the gaps left by jobs that gave up on their nonce.
+    let chain_ids: Vec<u64> = read_state(|s| s.chains.keys().copied().collect());
+    for chain_id in chain_ids {
+        let (reconciled, cycles) = measure(reconcile_nonces(chain_id)).await;
+        mutate_state(|s| s.cycles_spent += CyclesSpent::rpc(cycles));
+        if let Err(e) = reconciled {
+            println!(
+                "failed to reconcile the nonces on chain {}: {}",
+                chain_id, e
+            );
+        }
+    }
 
-    for (event_source, event) in logs_to_process {
-        job(event_source, event).await
+    // Jobs run concurrently, up to `max_jobs_in_flight` at a time, and each finished
+    // job starts the next one.
+    start_jobs();
+
+    let logs_to_compensate = read_state(|s| (s.logs_to_compensate.clone()));
+
+    for (event_source, job) in logs_to_compensate {
+        if read_state(|s| s.logs_to_compensate.contains_key(&event_source)) {
+            compensate(event_source, job).await
+        }
     }
 }
 
+/// Scrapes the chains that are due according to their scraping cadence and
+/// schedules the next scrape.
 pub async fn scrape_eth_logs() {
     let _guard = match TimerGuard::new(TaskType::ScrapeLogs) {
         Ok(guard) => guard,
-        Err(_) => return,
+        Err(_) => {
+            // The scrape holding the guard re-arms the timer when it finishes. If it
+            // trapped it never does, so the timer is re-armed here to try again once
+            // the guard's lease has expired.
+            schedule_scraping_in(crate::SCRAPING_LOGS_INTERVAL);
+            return;
+        }
     };
-    let rpc_service = read_state(|s| s.rpc_service.clone());
-    let config = IcpConfig::new(rpc_service).set_max_response_size(100_000);
-    let provider = ProviderBuilder::new().on_icp(config);
-    let addresses = read_state(State::get_filter_addresses);
-    let events = read_state(State::get_filter_events);
 
-    // This callback will be called every time new logs are received
-    let callback = |incoming_logs: Vec<Log>| {
-        for log in incoming_logs.iter() {
-            mutate_state(|s| s.record_log_to_process(log));
+    // Push the due chains back by their current interval before scraping them, so
+    // that scraping goes on even if one of the scrapes below traps.
+    let now = ic_cdk::api::time();
+    let due_chain_ids: Vec<u64> = mutate_state(|s| {
+        s.chains
+            .values_mut()
+            .filter(|chain| chain.next_scrape_at <= now)
+            .map(|chain| {
+                chain.record_scrape(false, now);
+                chain.chain_id
+            })
+            .collect()
+    });
+    schedule_scraping();
+
+    if read_state(|s| s.scraping_paused) || !check_cycles_balance() {
+        return;
+    }
+    for chain_id in due_chain_ids {
+        let (found_logs, cycles) = measure(scrape_chain(chain_id)).await;
+        mutate_state(|s| s.cycles_spent += CyclesSpent::scraping(cycles));
+        if found_logs {
+            mutate_state(|s| {
+                s.chain_mut(chain_id)
+                    .record_scrape(true, ic_cdk::api::time())
+            });
         }
-        if read_state(State::has_logs_to_process) {
-            ic_cdk_timers::set_timer(
-                Duration::from_secs(0),
-                move || ic_cdk::spawn(process_logs()),
+    }
+    schedule_scraping();
+
+    if read_state(State::has_logs_to_process) {
+        schedule_process_logs();
+    }
+}
+
+/// (Re-)arms the scraping timer for the chain that is due next.
+pub fn schedule_scraping() {
+    let Some(next_scrape_at) = read_state(|s| s.chains.values().map(|c| c.next_scrape_at).min())
+    else {
+        return;
+    };
+    let delay = Duration::from_nanos(next_scrape_at.saturating_sub(ic_cdk::api::time()));
+    schedule_scraping_in(delay);
+}
+
+/// (Re-)arms the scraping timer to fire after `delay`.
+pub fn schedule_scraping_in(delay: Duration) {
+    let timer_id = ic_cdk_timers::set_timer(delay, || ic_cdk::spawn(scrape_eth_logs()));
+    if let Some(previous_timer_id) = mutate_state(|s| s.scraping_timer.replace(timer_id)) {
+        ic_cdk_timers::clear_timer(previous_timer_id);
+    }
+}
+
+/// Scrapes the logs of one chain, from the block after the last scraped block up to
+/// the latest confirmed block. Returns whether any logs were found.
+async fn scrape_chain(chain_id: u64) -> bool {
+    let chain = read_state(|s| s.chain(chain_id).clone());
+    let config = IcpConfig::new(chain.rpc_service.clone()).set_max_response_size(MAX_RESPONSE_SIZE);
+    let provider = ProviderBuilder::new().on_icp(config);
+    let addresses = chain.filter_addresses;
+    let events = chain.filter_events;
+
+    // Only scrape blocks that satisfy the confirmation policy, so that no job is run
+    // for an event that a reorg can still undo.
+    let confirmation_policy = chain.confirmation_policy;
+    let latest_block = match confirmed_block_number(&provider, confirmation_policy).await {
+        Ok(block_number) => block_number,
+        Err(e) => {
+            println!(
+                "failed to get the latest block on chain {} for {:?}: {}",
+                chain_id, confirmation_policy, e
             );
+            return false;
         }
     };
+    if let Err(e) = detect_reorg(&provider, &chain.scraping_backend, chain_id).await {
+        println!(
+            "failed to check the scraped blocks of chain {} for reorgs: {}",
+            chain_id, e
+        );
+        return false;
+    }
+
+    // Resume right after the last scraped block, so that no range is ever skipped.
+    // Before the first successful scrape we start at the configured start block or,
+    // if there is none, at the latest confirmed block.
+    let mut from_block =
+        read_state(|s| s.chain(chain_id).next_block_to_scrape()).unwrap_or(latest_block);
 
+    let scraping_backend = chain.scraping_backend;
     let filter = Filter::new()
-        .address(addresses)
+        .address(addresses.clone())
         // By specifying an `event` or `event_signature` we listen for a specific event of the
         // contract. In this case the `Transfer(address,address,uint256)` event.
         // .event(Coprocessor::NewJob::SIGNATURE)
-        .events(events)
-        .from_block(BlockNumberOrTag::Latest);
-
-    // Initialize the poller and start watching
-    // `with_poll_interval` (optional) is used to set the interval between polls, defaults to 7 seconds
-    let poller = provider.watch_logs(&filter).await.unwrap();
-    let _timer_id = poller
-        .with_poll_interval(SCRAPING_LOGS_INTERVAL)
-        .start(callback)
-        .unwrap();
+        .events(events.clone());
+
+    // Fetch the logs in windows of `block_range` blocks. The window is halved whenever
+    // the response does not fit into `MAX_RESPONSE_SIZE` and grows again after each
+    // successful request. A single block whose logs still do not fit is fetched with a
+    // larger response size, up to `MAX_SINGLE_BLOCK_RESPONSE_SIZE`. Progress is
+    // recorded after every window, so a single busy range never blocks the logs that
+    // were already fetched.
+    let mut found_logs = false;
+    let mut max_response_size = MAX_RESPONSE_SIZE;
+    while from_block <= latest_block {
+        let block_range = read_state(|s| s.chain(chain_id).block_range);
+        let to_block = latest_block.min(from_block.saturating_add(block_range - 1));
+        let (result, cycles) = measure(async {
+            // the header is fetched before the logs, so that a reorg in between leaves
+            // a hash that no longer matches and is rescanned by `detect_reorg`
+            let to_block_hash = get_scraped_block_header(
+                &provider,
+                &scraping_backend,
+                BlockNumberOrTag::Number(to_block),
+            )
+            .await
+            .map_err(GetLogsError::Other)?
+            .hash;
+            let logs = match &scraping_backend {
+                ScrapingBackend::Provider => {
+                    let config = IcpConfig::new(chain.rpc_service.clone())
+                        .set_max_response_size(max_response_size);
+                    let window = filter.clone().from_block(from_block).to_block(to_block);
+                    ProviderBuilder::new()
+                        .on_icp(config)
+                        .get_logs(&window)
+                        .await
+                        .map_err(|e| GetLogsError::from_transport_error(e.to_string()))?
+                }
+                ScrapingBackend::EvmRpc(config) => {
+                    evm_rpc::get_logs(
+                        config,
+                        &addresses,
+                        &events,
+                        from_block,
+                        to_block,
+                        max_response_size,
+                    )
+                    .await?
+                }
+            };
+            check_block_hash(&logs, to_block, to_block_hash).map_err(GetLogsError::Other)?;
+            Ok((logs, to_block_hash))
+        })
+        .await;
+
+        match result {
+            Ok((logs, to_block_hash)) => {
+                mutate_state(|s| {
+                    // the jobs share the cycles of the request that returned their logs
+                    let share = cycles / logs.len().max(1) as u128;
+                    for log in logs.iter() {
+                        s.record_log_to_process(chain_id, log);
+                        if let Some(job) = s.logs_to_process.get_mut(&log.source()) {
+                            job.cycles += CyclesSpent::scraping(share);
+                        }
+                    }
+                    let chain = s.chain_mut(chain_id);
+                    chain.last_scraped_block = Some(to_block);
+                    chain.record_scraped_block_hash(to_block, to_block_hash);
+                    chain.block_range = grow_block_range(block_range);
+                    chain.inconsistent_scrapes = 0;
+                });
+                max_response_size = MAX_RESPONSE_SIZE;
+                if !logs.is_empty() {
+                    found_logs = true;
+                    schedule_process_logs();
+                }
+                from_block = to_block + 1;
+            }
+            Err(GetLogsError::ResponseTooLarge(e)) => {
+                match shrink_request(block_range, max_response_size) {
+                    ShrunkRequest::BlockRange(block_range) => {
+                        println!(
+                            "logs for blocks {}..={} on chain {} exceed the max response size, halving the block range",
+                            from_block, to_block, chain_id
+                        );
+                        mutate_state(|s| s.chain_mut(chain_id).block_range = block_range);
+                    }
+                    ShrunkRequest::SingleBlock(response_size) => {
+                        max_response_size = response_size;
+                        println!(
+                            "logs for block {} on chain {} exceed the max response size, raising it to {} bytes",
+                            from_block, chain_id, max_response_size
+                        );
+                    }
+                    ShrunkRequest::Stuck => {
+                        let message = format!(
+                            "the logs of block {} on chain {} exceed the largest response size of {} bytes, scraping the chain is stuck until its filters are narrowed: {}",
+                            from_block, chain_id, MAX_SINGLE_BLOCK_RESPONSE_SIZE, e
+                        );
+                        println!("{}", message);
+                        // the block is tried again with every scrape, but raises a single alert
+                        mutate_state(|s| {
+                            if !s
+                                .alerts
+                                .last()
+                                .is_some_and(|alert| alert.message == message)
+                            {
+                                s.record_alert(message, ic_cdk::api::time());
+                            }
+                        });
+                        break;
+                    }
+                }
+            }
+            Err(GetLogsError::Inconsistent(e)) => {
+                println!("{}, backing off", e);
+                mutate_state(|s| {
+                    let now = ic_cdk::api::time();
+                    let inconsistent_scrapes =
+                        s.chain_mut(chain_id).record_inconsistent_scrape(now);
+                    if inconsistent_scrapes == MAX_INCONSISTENT_SCRAPES {
+                        s.record_alert(
+                            format!("{} {} times in a row", e, inconsistent_scrapes),
+                            now,
+                        );
+                    }
+                });
+                break;
+            }
+            Err(GetLogsError::Other(e)) => {
+                println!(
+                    "failed to get logs for blocks {}..={} on chain {}: {}",
+                    from_block, to_block, chain_id, e
+                );
+                break;
+            }
+        }
+    }
+    found_logs
+}
+
+/// The subset of the fields of a block returned by `eth_getBlockByNumber` that the
+/// scraper needs.
+#[derive(Debug, Deserialize)]
+struct BlockHeader {
+    number: U64,
+    hash: B256,
+}
+
+async fn get_block_header<T, P>(
+    provider: &P,
+    block: BlockNumberOrTag,
+) -> TransportResult<BlockHeader>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    let header: Option<BlockHeader> = provider
+        .raw_request("eth_getBlockByNumber".into(), (block, false))
+        .await?;
+    header.ok_or_else(|| TransportErrorKind::custom_str(&format!("block {} not found", block)))
+}
+
+/// Checks that the logs of `to_block` are from the block with `to_block_hash`, i.e.
+/// that the chain was not reorganized between fetching the header and the logs.
+fn check_block_hash(logs: &[Log], to_block: u64, to_block_hash: B256) -> Result<(), String> {
+    match logs
+        .iter()
+        .find(|log| log.block_number == Some(to_block) && log.block_hash != Some(to_block_hash))
+    {
+        Some(log) => Err(format!(
+            "block {} was reorganized while its logs were fetched, got a log of block {:?} instead of {}",
+            to_block, log.block_hash, to_block_hash
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Fetches the header of `block` from the providers that the logs are scraped from.
+async fn get_scraped_block_header<T, P>(
+    provider: &P,
+    scraping_backend: &ScrapingBackend,
+    block: BlockNumberOrTag,
+) -> Result<BlockHeader, String>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    match scraping_backend {
+        ScrapingBackend::Provider => get_block_header(provider, block)
+            .await
+            .map_err(|e| e.to_string()),
+        ScrapingBackend::EvmRpc(config) => evm_rpc::get_block_header(config, block).await,
+    }
+}
+
+/// Compares the hashes of the recently scraped ranges of `chain_id` with the canonical
+/// chain. If they differ, the scraper is rolled back to the most recent block that is
+/// still canonical, the logs of all later blocks are reverted and scraped again.
+async fn detect_reorg<T, P>(
+    provider: &P,
+    scraping_backend: &ScrapingBackend,
+    chain_id: u64,
+) -> Result<(), String>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    let scraped_block_hashes: Vec<(u64, B256)> = read_state(|s| {
+        s.chain(chain_id)
+            .scraped_block_hashes
+            .iter()
+            .rev()
+            .map(|(number, hash)| (*number, *hash))
+            .collect()
+    });
+    let Some(&(oldest_block, _)) = scraped_block_hashes.last() else {
+        return Ok(());
+    };
+
+    let mut common_block = None;
+    for (index, (number, hash)) in scraped_block_hashes.iter().enumerate() {
+        let header = get_scraped_block_header(
+            provider,
+            scraping_backend,
+            BlockNumberOrTag::Number(*number),
+        )
+        .await?;
+        if header.hash == *hash {
+            if index == 0 {
+                return Ok(());
+            }
+            common_block = Some(*number);
+            break;
+        }
+    }
+
+    let rollback_block = common_block.unwrap_or_else(|| {
+        println!(
+            "reorg is deeper than the tracked block hashes, rescanning from block {}",
+            oldest_block
+        );
+        oldest_block.saturating_sub(1)
+    });
+    println!(
+        "detected reorg on chain {}, rolling back to block {}",
+        chain_id, rollback_block
+    );
+    mutate_state(|s| s.rollback_to(chain_id, rollback_block));
+    Ok(())
+}
+
+/// Returns the number of the most recent block that satisfies `policy`.
+async fn confirmed_block_number<T, P>(
+    provider: &P,
+    policy: ConfirmationPolicy,
+) -> TransportResult<u64>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    let tag = match policy {
+        ConfirmationPolicy::Confirmations(confirmations) => {
+            let latest_block = provider.get_block_number().await?;
+            return Ok(latest_block.saturating_sub(confirmations));
+        }
+        ConfirmationPolicy::Safe => BlockNumberOrTag::Safe,
+        ConfirmationPolicy::Finalized => BlockNumberOrTag::Finalized,
+    };
+    let header = get_block_header(provider, tag).await?;
+    Ok(header.number.to::<u64>())
+}
+
+pub fn schedule_process_logs() {
+    ic_cdk_timers::set_timer(
+        Duration::from_secs(0),
+        move || ic_cdk::spawn(process_logs()),
+    );
+}
+
+/// How the request for the logs of a window is shrunk after its response exceeded
+/// the max response size, see `shrink_request`.
+#[derive(Debug, PartialEq, Eq)]
+enum ShrunkRequest {
+    /// The window is retried with this many blocks.
+    BlockRange(u64),
+    /// The single block is retried with a response size of this many bytes.
+    SingleBlock(u64),
+    /// The logs of the single block do not fit into the largest response.
+    Stuck,
+}
+
+/// Halves the window of `block_range` blocks whose logs did not fit into
+/// `max_response_size` bytes, or raises the response size once the window is a single
+/// block.
+fn shrink_request(block_range: u64, max_response_size: u64) -> ShrunkRequest {
+    if block_range > 1 {
+        ShrunkRequest::BlockRange(block_range / 2)
+    } else if max_response_size < MAX_SINGLE_BLOCK_RESPONSE_SIZE {
+        ShrunkRequest::SingleBlock((max_response_size * 2).min(MAX_SINGLE_BLOCK_RESPONSE_SIZE))
+    } else {
+        ShrunkRequest::Stuck
+    }
+}
+
+/// Doubles the window after a successful request, up to `MAX_BLOCK_RANGE`.
+fn grow_block_range(block_range: u64) -> u64 {
+    (block_range * 2).min(MAX_BLOCK_RANGE)
+}
+
+/// Why the logs of a window could not be fetched.
+#[derive(Debug)]
+pub enum GetLogsError {
+    /// The response exceeded the maximum response size of the HTTPS outcall.
+    ResponseTooLarge(String),
+    /// The providers of the EVM RPC canister did not agree on the logs.
+    Inconsistent(String),
+    Other(String),
+}
+
+impl GetLogsError {
+    /// Classifies an error of ic-alloy, which only passes on the rejection of the
+    /// HTTPS outcall as text.
+    fn from_transport_error(error: String) -> Self {
+        if error.contains("size limit") || error.contains("max_response_bytes") {
+            Self::ResponseTooLarge(error)
+        } else {
+            Self::Other(error)
+        }
+    }
+}
+
+impl std::fmt::Display for GetLogsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ResponseTooLarge(e) | Self::Inconsistent(e) | Self::Other(e) => f.write_str(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_halve_the_block_range_down_to_a_single_block() {
+        let mut block_range = MAX_BLOCK_RANGE;
+        let mut ranges = vec![];
+        while let ShrunkRequest::BlockRange(shrunk) = shrink_request(block_range, MAX_RESPONSE_SIZE)
+        {
+            ranges.push(shrunk);
+            block_range = shrunk;
+        }
+        assert_eq!(ranges, vec![250, 125, 62, 31, 15, 7, 3, 1]);
+    }
+
+    #[test]
+    fn should_grow_the_block_range_back_up_to_the_max() {
+        assert_eq!(grow_block_range(1), 2);
+        assert_eq!(grow_block_range(200), 400);
+        assert_eq!(grow_block_range(400), MAX_BLOCK_RANGE);
+        assert_eq!(grow_block_range(MAX_BLOCK_RANGE), MAX_BLOCK_RANGE);
+    }
+
+    #[test]
+    fn should_raise_the_response_size_of_a_single_block_that_is_too_large() {
+        assert_eq!(
+            shrink_request(1, MAX_RESPONSE_SIZE),
+            ShrunkRequest::SingleBlock(2 * MAX_RESPONSE_SIZE)
+        );
+        assert_eq!(
+            shrink_request(1, 1_600_000),
+            ShrunkRequest::SingleBlock(MAX_SINGLE_BLOCK_RESPONSE_SIZE)
+        );
+        assert_eq!(
+            shrink_request(1, MAX_SINGLE_BLOCK_RESPONSE_SIZE),
+            ShrunkRequest::Stuck
+        );
+    }
 }
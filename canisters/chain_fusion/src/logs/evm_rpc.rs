@@ -0,0 +1,186 @@
+use std::str::FromStr;
+
+use alloy::eips::BlockNumberOrTag;
+use alloy::primitives::{keccak256, Address, Bytes, B256, U64};
+use alloy::rpc::types::Log;
+use candid::Nat;
+use evm_rpc_canister_types::{
+    BlockTag, GetBlockByNumberResult, GetLogsArgs, GetLogsResult, HttpOutcallError, LogEntry,
+    MultiGetBlockByNumberResult, MultiGetLogsResult, RejectionCode, RpcConfig, RpcError,
+    RpcService, EVM_RPC,
+};
+
+use super::{BlockHeader, GetLogsError};
+use crate::state::EvmRpcScraping;
+
+/// The cycles attached to each `eth_getLogs` call per 100 kB of the maximum response
+/// size. Unused cycles are refunded by the EVM RPC canister.
+const GET_LOGS_CYCLES: u128 = 10_000_000_000;
+/// The cycles attached to each `eth_getBlockByNumber` call.
+const GET_BLOCK_CYCLES: u128 = 10_000_000_000;
+
+/// Fetches the logs of `addresses` and `events` in the blocks `from_block..=to_block`
+/// from all providers in `config` and returns them if the providers agree according to
+/// the consensus strategy.
+pub async fn get_logs(
+    config: &EvmRpcScraping,
+    addresses: &[Address],
+    events: &[String],
+    from_block: u64,
+    to_block: u64,
+    max_response_size: u64,
+) -> Result<Vec<Log>, GetLogsError> {
+    let args = GetLogsArgs {
+        fromBlock: Some(BlockTag::Number(Nat::from(from_block))),
+        toBlock: Some(BlockTag::Number(Nat::from(to_block))),
+        addresses: addresses
+            .iter()
+            .map(|address| address.to_string())
+            .collect(),
+        topics: Some(vec![events
+            .iter()
+            .map(|event| keccak256(event.as_bytes()).to_string())
+            .collect()]),
+    };
+    let rpc_config = RpcConfig {
+        responseConsensus: Some(config.consensus.clone()),
+        responseSizeEstimate: Some(max_response_size),
+    };
+    let cycles = GET_LOGS_CYCLES * (max_response_size as u128 / 100_000).max(1);
+
+    let (result,) = EVM_RPC
+        .eth_get_logs(config.rpc_services.clone(), Some(rpc_config), args, cycles)
+        .await
+        .map_err(|(code, message)| {
+            GetLogsError::Other(format!(
+                "failed to call the EVM RPC canister: {:?} {}",
+                code, message
+            ))
+        })?;
+    match result {
+        MultiGetLogsResult::Consistent(GetLogsResult::Ok(entries)) => entries
+            .into_iter()
+            .map(into_log)
+            .collect::<Result<_, _>>()
+            .map_err(GetLogsError::Other),
+        // the HTTPS outcall is rejected with `SysFatal` if the response exceeds
+        // `max_response_bytes`
+        MultiGetLogsResult::Consistent(GetLogsResult::Err(RpcError::HttpOutcallError(
+            HttpOutcallError::IcError {
+                code: RejectionCode::SysFatal,
+                message,
+            },
+        ))) => Err(GetLogsError::ResponseTooLarge(message)),
+        MultiGetLogsResult::Consistent(GetLogsResult::Err(e)) => {
+            Err(GetLogsError::Other(format!("{:?}", e)))
+        }
+        // the request is sent again with a later scrape, see
+        // `ChainState::record_inconsistent_scrape`
+        MultiGetLogsResult::Inconsistent(results) => Err(GetLogsError::Inconsistent(format!(
+            "the providers {} returned inconsistent logs for blocks {}..={}",
+            providers(&results),
+            from_block,
+            to_block
+        ))),
+    }
+}
+
+/// Fetches the header of `block` from all providers in `config`, so that the block
+/// hashes that reorgs are detected with come from the same providers as the logs.
+pub async fn get_block_header(
+    config: &EvmRpcScraping,
+    block: BlockNumberOrTag,
+) -> Result<BlockHeader, String> {
+    let block_tag = match block {
+        BlockNumberOrTag::Latest => BlockTag::Latest,
+        BlockNumberOrTag::Finalized => BlockTag::Finalized,
+        BlockNumberOrTag::Safe => BlockTag::Safe,
+        BlockNumberOrTag::Earliest => BlockTag::Earliest,
+        BlockNumberOrTag::Pending => BlockTag::Pending,
+        BlockNumberOrTag::Number(number) => BlockTag::Number(Nat::from(number)),
+    };
+    let rpc_config = RpcConfig {
+        responseConsensus: Some(config.consensus.clone()),
+        responseSizeEstimate: None,
+    };
+    let (result,) = EVM_RPC
+        .eth_get_block_by_number(
+            config.rpc_services.clone(),
+            Some(rpc_config),
+            block_tag,
+            GET_BLOCK_CYCLES,
+        )
+        .await
+        .map_err(|(code, message)| {
+            format!(
+                "failed to call the EVM RPC canister: {:?} {}",
+                code, message
+            )
+        })?;
+    match result {
+        MultiGetBlockByNumberResult::Consistent(GetBlockByNumberResult::Ok(block)) => {
+            Ok(BlockHeader {
+                number: U64::from(nat_to_u64(&block.number)?),
+                hash: parse_hash(&block.hash)?,
+            })
+        }
+        MultiGetBlockByNumberResult::Consistent(GetBlockByNumberResult::Err(e)) => {
+            Err(format!("{:?}", e))
+        }
+        MultiGetBlockByNumberResult::Inconsistent(results) => Err(format!(
+            "the providers {} returned inconsistent headers for block {}",
+            providers(&results),
+            block
+        )),
+    }
+}
+
+/// Lists the providers of inconsistent results.
+fn providers<T>(results: &[(RpcService, T)]) -> String {
+    results
+        .iter()
+        .map(|(service, _)| format!("{:?}", service))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn into_log(entry: LogEntry) -> Result<Log, String> {
+    let topics = entry
+        .topics
+        .iter()
+        .map(|topic| B256::from_str(topic))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid topic: {}", e))?;
+    let inner = alloy::primitives::Log::new(
+        Address::from_str(&entry.address).map_err(|e| format!("invalid address: {}", e))?,
+        topics,
+        Bytes::from_str(&entry.data).map_err(|e| format!("invalid data: {}", e))?,
+    )
+    .ok_or("a log has at most four topics")?;
+    Ok(Log {
+        inner,
+        block_hash: entry.blockHash.as_deref().map(parse_hash).transpose()?,
+        block_number: entry.blockNumber.as_ref().map(nat_to_u64).transpose()?,
+        block_timestamp: None,
+        transaction_hash: entry
+            .transactionHash
+            .as_deref()
+            .map(parse_hash)
+            .transpose()?,
+        transaction_index: entry
+            .transactionIndex
+            .as_ref()
+            .map(nat_to_u64)
+            .transpose()?,
+        log_index: entry.logIndex.as_ref().map(nat_to_u64).transpose()?,
+        removed: entry.removed,
+    })
+}
+
+fn parse_hash(hash: &str) -> Result<B256, String> {
+    B256::from_str(hash).map_err(|e| format!("invalid hash {}: {}", hash, e))
+}
+
+fn nat_to_u64(n: &Nat) -> Result<u64, String> {
+    u64::try_from(&n.0).map_err(|e| format!("invalid number {}: {}", n, e))
+}
@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+
+use alloy::network::{EthereumWallet, TransactionBuilder};
+use alloy::primitives::{TxHash, B256, U256};
+use alloy::providers::{Provider, ProviderBuilder};
+use alloy::rpc::types::TransactionRequest;
+use alloy::transports::icp::IcpConfig;
+use candid::{CandidType, Deserialize};
+use ic_cdk::println;
+use serde::Serialize;
+
+use crate::endpoints;
+use crate::state::{mutate_state, read_state, LogSource};
+
+/// Hands out the nonces of the canister's EVM address on one chain and keeps track of
+/// the transactions sent with them until they are mined.
+///
+/// Every job reserves its own nonce before it sends its result transaction. A nonce
+/// whose job gives up before the transaction is mined leaves a gap that would block
+/// all later transactions, so `reconcile` compares the handed out nonces with the
+/// transaction counts reported by the RPC provider and returns the gaps to fill.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NonceManager {
+    /// The next nonce to hand out, `None` until the first reconciliation.
+    next_nonce: Option<u64>,
+    /// The handed out nonces that have not been mined yet.
+    in_flight: BTreeMap<u64, InFlightTransaction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InFlightTransaction {
+    /// The job that reserved the nonce, `None` once the job released it again or if
+    /// the nonce is used to fill a gap.
+    pub owner: Option<LogSource>,
+    /// The hash of the last transaction sent with this nonce.
+    pub tx_hash: Option<B256>,
+}
+
+impl NonceManager {
+    /// Reserves the next nonce for the job `owner`. Returns `None` if the nonces
+    /// have not been reconciled with the RPC provider yet.
+    pub fn reserve(&mut self, owner: LogSource) -> Option<u64> {
+        let nonce = self.next_nonce?;
+        self.next_nonce = Some(nonce + 1);
+        self.in_flight.insert(
+            nonce,
+            InFlightTransaction {
+                owner: Some(owner),
+                tx_hash: None,
+            },
+        );
+        Some(nonce)
+    }
+
+    pub fn record_sent(&mut self, nonce: u64, tx_hash: B256) {
+        self.in_flight
+            .entry(nonce)
+            .or_insert(InFlightTransaction {
+                owner: None,
+                tx_hash: None,
+            })
+            .tx_hash = Some(tx_hash);
+    }
+
+    /// Releases the nonce of a job that will not send (or resend) its transaction,
+    /// turning it into a gap unless its transaction is mined anyway.
+    pub fn release(&mut self, nonce: u64) {
+        if let Some(tx) = self.in_flight.get_mut(&nonce) {
+            tx.owner = None;
+        }
+    }
+
+    /// Reconciles the handed out nonces with the transaction count of the canister's
+    /// EVM address at the `latest` and the `pending` block, and returns the nonces
+    /// that have to be filled.
+    ///
+    /// Nonces below `latest` are mined and no longer tracked. Nonces between `latest`
+    /// and `pending` are used by transactions in the mempool. Of the nonces from
+    /// `pending` on, those without a job are gaps: trailing gaps are cancelled by
+    /// handing the nonces out again, the others have to be filled with a transaction.
+    pub fn reconcile(&mut self, latest: u64, pending: u64) -> Vec<u64> {
+        let pending = pending.max(latest);
+        self.in_flight.retain(|nonce, _| *nonce >= latest);
+
+        // transactions sent from outside of the canister advance the nonce as well
+        let mut next_nonce = self.next_nonce.unwrap_or(pending).max(pending);
+        while next_nonce > pending && self.is_gap(next_nonce - 1) {
+            next_nonce -= 1;
+            self.in_flight.remove(&next_nonce);
+        }
+        self.next_nonce = Some(next_nonce);
+
+        (pending..next_nonce)
+            .filter(|nonce| self.is_gap(*nonce))
+            .collect()
+    }
+
+    fn is_gap(&self, nonce: u64) -> bool {
+        !self
+            .in_flight
+            .get(&nonce)
+            .is_some_and(|tx| tx.owner.is_some())
+    }
+}
+
+/// The Candid representation of the `NonceManager`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct NonceStatus {
+    pub chain_id: u64,
+    pub next_nonce: Option<u64>,
+    pub in_flight: Vec<InFlightTransactionInfo>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InFlightTransactionInfo {
+    pub nonce: u64,
+    pub log_source: Option<endpoints::LogSource>,
+    pub tx_hash: Option<String>,
+}
+
+impl NonceStatus {
+    pub fn new(chain_id: u64, nonces: &NonceManager) -> Self {
+        Self {
+            chain_id,
+            next_nonce: nonces.next_nonce,
+            in_flight: nonces
+                .in_flight
+                .iter()
+                .map(|(nonce, tx)| InFlightTransactionInfo {
+                    nonce: *nonce,
+                    log_source: tx.owner.as_ref().map(endpoints::LogSource::from),
+                    tx_hash: tx.tx_hash.map(|tx_hash| tx_hash.to_string()),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Reserves the next nonce of the canister's EVM address on `chain_id` for the job
+/// `owner`.
+pub async fn reserve_nonce(chain_id: u64, owner: LogSource) -> Result<u64, String> {
+    if read_state(|s| s.chain(chain_id).nonces.next_nonce.is_none()) {
+        reconcile_nonces(chain_id).await?;
+    }
+    mutate_state(|s| s.chain_mut(chain_id).nonces.reserve(owner))
+        .ok_or_else(|| "the nonces are not reconciled yet".to_string())
+}
+
+/// Fetches the transaction counts of the canister's EVM address on `chain_id`,
+/// reconciles the nonces with them and fills the gaps.
+pub async fn reconcile_nonces(chain_id: u64) -> Result<(), String> {
+    let evm_address = read_state(|s| s.canister_evm_address)
+        .ok_or("the canister's EVM address is not initialized yet")?;
+    let rpc_service = read_state(|s| s.chain(chain_id).rpc_service.clone());
+    let provider = ProviderBuilder::new().on_icp(IcpConfig::new(rpc_service));
+    let latest = provider
+        .get_transaction_count(evm_address)
+        .latest()
+        .await
+        .map_err(|e| e.to_string())?;
+    let pending = provider
+        .get_transaction_count(evm_address)
+        .pending()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let gaps = mutate_state(|s| s.chain_mut(chain_id).nonces.reconcile(latest, pending));
+    for nonce in gaps {
+        match fill_gap(chain_id, nonce).await {
+            Ok(tx_hash) => {
+                mutate_state(|s| s.chain_mut(chain_id).nonces.record_sent(nonce, tx_hash))
+            }
+            Err(e) => println!("failed to fill the gap at nonce {}: {}", nonce, e),
+        }
+    }
+    Ok(())
+}
+
+/// Fills the gap at `nonce` with a transfer of zero ether to the canister itself.
+async fn fill_gap(chain_id: u64, nonce: u64) -> Result<TxHash, String> {
+    let signer = read_state(|s| s.signer.clone()).ok_or("the signer is not initialized yet")?;
+    let evm_address = read_state(|s| s.canister_evm_address)
+        .ok_or("the canister's EVM address is not initialized yet")?;
+    let rpc_service = read_state(|s| s.chain(chain_id).rpc_service.clone());
+    let provider = ProviderBuilder::new()
+        .with_gas_estimation()
+        .wallet(EthereumWallet::new(signer))
+        .on_icp(IcpConfig::new(rpc_service));
+
+    let tx = TransactionRequest::default()
+        .with_from(evm_address)
+        .with_to(evm_address)
+        .with_value(U256::ZERO)
+        .with_nonce(nonce)
+        .with_chain_id(chain_id);
+    let pending_tx = provider
+        .send_transaction(tx)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(*pending_tx.tx_hash())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::FixedBytes;
+
+    fn job(id: u8) -> LogSource {
+        LogSource {
+            transaction_hash: FixedBytes::repeat_byte(id),
+            log_index: 0,
+        }
+    }
+
+    /// Returns a manager that handed out the nonces `0..n` to the jobs `0..n`.
+    fn reserved(n: u8) -> NonceManager {
+        let mut nonces = NonceManager::default();
+        assert_eq!(nonces.reconcile(0, 0), Vec::<u64>::new());
+        for id in 0..n {
+            assert_eq!(nonces.reserve(job(id)), Some(id as u64));
+        }
+        nonces
+    }
+
+    #[test]
+    fn should_not_reserve_before_the_first_reconciliation() {
+        let mut nonces = NonceManager::default();
+        assert_eq!(nonces.reserve(job(0)), None);
+
+        assert_eq!(nonces.reconcile(3, 5), Vec::<u64>::new());
+        assert_eq!(nonces.reserve(job(0)), Some(5));
+    }
+
+    #[test]
+    fn should_return_a_released_nonce_before_a_reserved_one_as_gap() {
+        let mut nonces = reserved(3);
+        nonces.record_sent(0, B256::repeat_byte(1));
+        nonces.release(1);
+
+        // only the transaction with nonce 0 is in the mempool
+        assert_eq!(nonces.reconcile(0, 1), vec![1]);
+        assert_eq!(nonces.next_nonce, Some(3));
+        assert!(nonces.in_flight.contains_key(&2));
+    }
+
+    #[test]
+    fn should_cancel_trailing_released_nonces() {
+        let mut nonces = reserved(3);
+        nonces.record_sent(0, B256::repeat_byte(1));
+        nonces.release(1);
+        nonces.release(2);
+
+        assert_eq!(nonces.reconcile(0, 1), Vec::<u64>::new());
+        assert_eq!(nonces.next_nonce, Some(1));
+        assert!(!nonces.in_flight.contains_key(&1));
+        assert!(!nonces.in_flight.contains_key(&2));
+        assert_eq!(nonces.reserve(job(3)), Some(1));
+    }
+
+    #[test]
+    fn should_keep_a_released_nonce_whose_transaction_is_pending() {
+        let mut nonces = reserved(2);
+        nonces.record_sent(0, B256::repeat_byte(1));
+        nonces.record_sent(1, B256::repeat_byte(2));
+        nonces.release(1);
+
+        // the transaction of the released nonce made it into the mempool anyway
+        assert_eq!(nonces.reconcile(0, 2), Vec::<u64>::new());
+        assert_eq!(nonces.next_nonce, Some(2));
+    }
+
+    #[test]
+    fn should_drop_mined_nonces() {
+        let mut nonces = reserved(3);
+        nonces.record_sent(0, B256::repeat_byte(1));
+        nonces.record_sent(1, B256::repeat_byte(2));
+
+        assert_eq!(nonces.reconcile(2, 2), Vec::<u64>::new());
+        assert_eq!(
+            nonces.in_flight.keys().copied().collect::<Vec<_>>(),
+            vec![2]
+        );
+        assert_eq!(nonces.next_nonce, Some(3));
+    }
+
+    #[test]
+    fn should_skip_nonces_used_by_external_transactions() {
+        let mut nonces = reserved(2);
+        nonces.record_sent(0, B256::repeat_byte(1));
+        nonces.record_sent(1, B256::repeat_byte(2));
+
+        // three more transactions were sent from the address outside of the canister
+        assert_eq!(nonces.reconcile(5, 5), Vec::<u64>::new());
+        assert!(nonces.in_flight.is_empty());
+        assert_eq!(nonces.reserve(job(2)), Some(5));
+    }
+}
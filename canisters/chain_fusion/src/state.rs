@@ -1,81 +1,1200 @@
-use alloy::primitives::{Address, FixedBytes};
+use alloy::primitives::{Address, FixedBytes, B256, U256};
 use alloy::rpc::types::Log;
 use alloy::signers::icp::IcpSigner;
 use alloy::transports::icp::RpcService;
 
+use candid::{CandidType, Deserialize, Principal};
+use evm_rpc_canister_types::{ConsensusStrategy, RpcServices};
 use ic_cdk::api::management_canister::ecdsa::EcdsaKeyId;
-use std::collections::{BTreeMap, HashSet};
+use ic_cdk_timers::TimerId;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
 
+use crate::attestation::AttestationKey;
+use crate::cycles::CyclesSpent;
+use crate::gas::GasBalance;
+use crate::guard::{self, JOB_GUARD_LEASE};
+use crate::nonce::NonceManager;
+use crate::storage;
 use std::cell::RefCell;
+use std::time::Duration;
 
 thread_local! {
     static STATE: RefCell<Option<State>> = RefCell::default();
 }
 
-#[derive(Debug, Clone)]
+/// The canister state. Everything except the signer and the currently active tasks
+/// is written to stable memory on upgrade, see `storage::save_state`. Processed jobs
+/// are not part of the state but kept directly in stable memory, see
+/// `storage::record_processed_job`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct State {
+    /// The watched EVM networks, keyed by chain id.
+    #[serde(default)]
+    pub chains: BTreeMap<u64, ChainState>,
+    /// Jobs that have not reached a final status yet.
+    pub logs_to_process: BTreeMap<LogSource, Job>,
+    /// Jobs that failed `retry_policy.max_attempts` times. Controllers can re-enqueue
+    /// them with `retry_dead_letter_job`.
+    #[serde(default)]
+    pub dead_letter_jobs: BTreeMap<LogSource, Job>,
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+    /// When result transactions that are not mined are replaced.
+    #[serde(default)]
+    pub resubmission_policy: ResubmissionPolicy,
+    /// Logs whose blocks were reorged out of the chain. They are re-queued once they
+    /// are scraped again.
+    #[serde(default)]
+    pub reverted_logs: BTreeMap<LogSource, RevertedLog>,
+    /// Processed jobs that were reverted and re-emitted and that are compensated
+    /// instead of run again, see `ReorgPolicy::Compensate`.
+    #[serde(default)]
+    pub logs_to_compensate: BTreeMap<LogSource, Job>,
+    /// How many of the processed jobs are kept in stable memory, see
+    /// `storage::record_processed_job`.
+    #[serde(default)]
+    pub retention_policy: RetentionPolicy,
+    /// The running tasks and when they acquired their guard, see `guard::TimerGuard`.
+    #[serde(skip)]
+    pub active_tasks: HashMap<TaskType, u64>,
+    /// How many jobs are run concurrently.
+    #[serde(default = "default_max_jobs_in_flight")]
+    pub max_jobs_in_flight: u32,
+    /// The running jobs and when they were started, see `guard::JobGuard`.
+    #[serde(skip)]
+    pub jobs_in_flight: BTreeMap<LogSource, u64>,
+    /// The attestations that are being renewed and when their renewal was started,
+    /// see `guard::RenewalGuard`.
+    #[serde(skip)]
+    pub renewals_in_progress: BTreeMap<AttestationKey, u64>,
+    #[serde(skip)]
+    pub signer: Option<IcpSigner>,
+    pub ecdsa_key_id: EcdsaKeyId,
+    /// The canister's EVM address, which is the same on all chains.
+    pub canister_evm_address: Option<Address>,
+    /// What happens to processed jobs whose log is re-emitted after a reorg.
+    #[serde(default)]
+    pub reorg_policy: ReorgPolicy,
+    /// The delay between installing or upgrading the canister and the first scrape.
+    #[serde(default = "default_initial_scraping_delay_secs")]
+    pub initial_scraping_delay_secs: u64,
+    /// The timer of the next scrape, see `logs::schedule_scraping`.
+    #[serde(skip)]
+    pub scraping_timer: Option<TimerId>,
+    #[serde(default)]
+    pub scraping_paused: bool,
+    #[serde(default)]
+    pub processing_paused: bool,
+    /// All configuration changes made by controllers at runtime.
+    #[serde(default)]
+    pub audit_log: Vec<AuditLogEntry>,
+    /// The most recent problems that need the attention of a controller.
+    #[serde(default)]
+    pub alerts: Vec<Alert>,
+    /// The cycles spent since the canister was installed.
+    #[serde(default)]
+    pub cycles_spent: CyclesSpent,
+    /// The cycles balance below which processing is paused, see
+    /// `cycles::check_cycles_balance`.
+    #[serde(default)]
+    pub min_cycles_balance: Option<u128>,
+    /// What jobs have to pay with the transaction that created them, `None` if
+    /// payments are not verified.
+    #[serde(default)]
+    pub payment_policy: Option<PaymentPolicy>,
+    /// How results are collected into batches, `None` if every result is sent in a
+    /// transaction of its own.
+    #[serde(default)]
+    pub batch_policy: Option<BatchPolicy>,
+    /// The timer of the next batch submission, see `job::schedule_batch_submission`.
+    #[serde(skip)]
+    pub batch_timer: Option<TimerId>,
+}
+
+/// The configuration and the scraping progress of one EVM network.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainState {
+    #[serde(with = "candid_encoded")]
     pub rpc_service: RpcService,
+    /// Where logs are scraped from. Block headers are always fetched from
+    /// `rpc_service`.
+    #[serde(default, with = "candid_encoded")]
+    pub scraping_backend: ScrapingBackend,
     pub chain_id: u64,
+    /// The coprocessor contract that receives the results posted to this chain.
     pub coprocessor_evm_address: Address,
+    /// The chain that the results of jobs from this chain are posted to, if it is
+    /// not this chain.
+    #[serde(default)]
+    pub result_chain_id: Option<u64>,
     pub filter_addresses: Vec<Address>,
     pub filter_events: Vec<String>,
-    pub logs_to_process: BTreeMap<LogSource, Log>,
-    pub processed_logs: BTreeMap<LogSource, Log>,
-    pub active_tasks: HashSet<TaskType>,
-    pub signer: Option<IcpSigner>,
-    pub ecdsa_key_id: EcdsaKeyId,
-    pub canister_evm_address: Option<Address>,
+    /// The nonces of the canister's EVM address on this chain, see `NonceManager`.
+    #[serde(default)]
+    pub nonces: NonceManager,
+    /// The block to start scraping from if no block has been scraped yet.
+    #[serde(default)]
+    pub start_block: Option<u64>,
+    /// The last block whose logs have been scraped successfully.
+    #[serde(default)]
+    pub last_scraped_block: Option<u64>,
+    /// Which blocks are considered final enough to scrape logs from.
+    #[serde(default)]
+    pub confirmation_policy: ConfirmationPolicy,
+    /// The current number of blocks requested per `eth_getLogs` call. It shrinks when
+    /// responses are too large and grows back up to `logs::MAX_BLOCK_RANGE`.
+    #[serde(default = "default_block_range")]
+    pub block_range: u64,
+    /// The hash of the last block of each recently scraped range, used to detect reorgs.
+    #[serde(default)]
+    pub scraped_block_hashes: BTreeMap<u64, B256>,
+    /// How often this chain is scraped.
+    #[serde(default)]
+    pub scraping_cadence: ScrapingCadence,
+    /// The current interval between two scrapes, which only changes in adaptive mode.
+    #[serde(default = "default_scraping_interval_secs")]
+    pub scraping_interval_secs: u64,
+    /// When this chain is scraped next, in nanoseconds since the epoch.
+    #[serde(default)]
+    pub next_scrape_at: u64,
+    /// The number of scrapes in a row whose providers returned inconsistent logs, by
+    /// which the next scrape is delayed, see `record_inconsistent_scrape`.
+    #[serde(default)]
+    pub inconsistent_scrapes: u32,
+    /// The last checked balance of the canister's EVM address on this chain, see
+    /// `gas::check_gas_balances`.
+    #[serde(default)]
+    pub gas_balance: Option<GasBalance>,
+    /// The gas used per result by the last result transaction mined on this chain,
+    /// i.e. a job's share of the gas of a batch.
+    #[serde(default)]
+    pub last_callback_gas_used: Option<u128>,
+    /// How the results posted to this chain reach its coprocessor contract.
+    #[serde(default)]
+    pub result_sink: ResultSink,
+}
+
+impl ChainState {
+    /// Returns the next block to scrape logs from, or `None` if scraping should
+    /// start at the latest block.
+    pub fn next_block_to_scrape(&self) -> Option<u64> {
+        self.last_scraped_block
+            .map(|block| block + 1)
+            .or(self.start_block)
+    }
+
+    pub fn record_scraped_block_hash(&mut self, block_number: u64, hash: B256) {
+        self.scraped_block_hashes.insert(block_number, hash);
+        while self.scraped_block_hashes.len() > MAX_SCRAPED_BLOCK_HASHES {
+            self.scraped_block_hashes.pop_first();
+        }
+    }
+
+    /// Returns the chain that the results of jobs from this chain are posted to.
+    pub fn result_chain_id(&self) -> u64 {
+        self.result_chain_id.unwrap_or(self.chain_id)
+    }
+
+    /// Whether the canister's EVM address can pay for the next `callbacks` result
+    /// transactions on this chain, as far as is known.
+    pub fn has_gas_for_callbacks(&self, callbacks: u64) -> bool {
+        !self
+            .gas_balance
+            .as_ref()
+            .is_some_and(|gas| !gas.covers(callbacks))
+    }
+
+    /// Lowers the last known balance by the estimated cost of `callbacks` results that
+    /// were just sent, so that the jobs started until the next balance check are not
+    /// paid with the same wei.
+    pub fn spend_gas_balance(&mut self, callbacks: u64) {
+        if let Some(gas) = self.gas_balance.as_mut() {
+            gas.balance = gas
+                .balance
+                .saturating_sub(gas.callback_cost.saturating_mul(U256::from(callbacks)));
+        }
+    }
+
+    pub fn set_scraping_cadence(&mut self, cadence: ScrapingCadence, now: u64) {
+        self.scraping_cadence = cadence;
+        self.scraping_interval_secs = cadence.initial_interval_secs();
+        self.next_scrape_at =
+            now.saturating_add(Duration::from_secs(self.scraping_interval_secs).as_nanos() as u64);
+    }
+
+    /// Schedules the next scrape after a scrape that ended at `now`.
+    pub fn record_scrape(&mut self, found_logs: bool, now: u64) {
+        self.scraping_interval_secs = self
+            .scraping_cadence
+            .next_interval_secs(self.scraping_interval_secs, found_logs);
+        self.next_scrape_at = now.saturating_add(self.scraping_delay().as_nanos() as u64);
+    }
+
+    /// Backs off after a scrape whose providers returned inconsistent logs, so that
+    /// the range is requested again after a longer delay rather than right away.
+    /// Returns the number of such scrapes in a row.
+    pub fn record_inconsistent_scrape(&mut self, now: u64) -> u32 {
+        self.inconsistent_scrapes += 1;
+        self.next_scrape_at = now.saturating_add(self.scraping_delay().as_nanos() as u64);
+        self.inconsistent_scrapes
+    }
+
+    /// The delay until the next scrape: the scraping interval, doubled for every
+    /// scrape in a row whose providers disagreed.
+    fn scraping_delay(&self) -> Duration {
+        let backoff = 1
+            << self
+                .inconsistent_scrapes
+                .min(MAX_INCONSISTENT_SCRAPES_BACKOFF);
+        Duration::from_secs(self.scraping_interval_secs.saturating_mul(backoff))
+    }
+}
+
+/// How often a chain is scraped for new logs.
+#[derive(CandidType, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScrapingCadence {
+    /// Scrapes every `interval_secs` seconds.
+    Fixed { interval_secs: u64 },
+    /// Scrapes every `min_interval_secs` seconds while logs are arriving and doubles
+    /// the interval after every scrape without logs, up to `max_interval_secs`.
+    Adaptive {
+        min_interval_secs: u64,
+        max_interval_secs: u64,
+    },
+}
+
+impl Default for ScrapingCadence {
+    fn default() -> Self {
+        Self::Fixed {
+            interval_secs: crate::SCRAPING_LOGS_INTERVAL.as_secs(),
+        }
+    }
+}
+
+impl ScrapingCadence {
+    pub fn initial_interval_secs(&self) -> u64 {
+        match *self {
+            Self::Fixed { interval_secs } => interval_secs,
+            Self::Adaptive {
+                min_interval_secs, ..
+            } => min_interval_secs,
+        }
+    }
+
+    /// Returns the interval after a scrape that followed an interval of
+    /// `current_secs` and did or did not find logs.
+    pub fn next_interval_secs(&self, current_secs: u64, found_logs: bool) -> u64 {
+        match *self {
+            Self::Fixed { interval_secs } => interval_secs,
+            Self::Adaptive {
+                min_interval_secs, ..
+            } if found_logs => min_interval_secs,
+            Self::Adaptive {
+                min_interval_secs,
+                max_interval_secs,
+            } => current_secs
+                .saturating_mul(2)
+                .clamp(min_interval_secs, max_interval_secs),
+        }
+    }
+}
+
+/// A configuration change made through one of the admin methods.
+#[derive(CandidType, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditLogEntry {
+    /// The time of the change in nanoseconds since the epoch.
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub change: String,
+}
+
+/// A problem that the canister cannot resolve on its own.
+#[derive(CandidType, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Alert {
+    /// The time of the alert in nanoseconds since the epoch.
+    pub timestamp: u64,
+    pub message: String,
+}
+
+/// The number of alerts that are kept.
+const MAX_ALERTS: usize = 100;
+/// The number of audit log entries that are kept.
+const MAX_AUDIT_LOG_ENTRIES: usize = 1_000;
+
+/// Where the scraper fetches logs from.
+#[derive(CandidType, Deserialize, Debug, Clone, Default)]
+pub enum ScrapingBackend {
+    /// `eth_getLogs` through ic-alloy with the single `rpc_service`.
+    #[default]
+    Provider,
+    /// `eth_getLogs` of the EVM RPC canister, sent to several providers whose
+    /// responses have to agree according to the consensus strategy.
+    EvmRpc(EvmRpcScraping),
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct EvmRpcScraping {
+    pub rpc_services: RpcServices,
+    /// How many providers have to return the same logs, e.g.
+    /// `Threshold { min: 2, total: Some(3) }` for two out of three.
+    pub consensus: ConsensusStrategy,
+}
+
+/// A job triggered by a scraped log, together with how far it got.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    /// The chain the log was scraped from.
+    #[serde(default)]
+    pub chain_id: u64,
+    pub log: Log,
+    pub status: JobStatus,
+    /// The job id decoded from the log.
+    #[serde(default)]
+    pub job_id: Option<U256>,
+    /// The computed result. Once set, it is never computed again.
+    #[serde(default)]
+    pub result: Option<String>,
+    /// The nonce reserved for the result transaction. Once set, the result is always
+    /// submitted with this nonce.
+    #[serde(default)]
     pub nonce: Option<u64>,
+    /// The chain the nonce was reserved on and the result is posted to.
+    #[serde(default)]
+    pub result_chain_id: Option<u64>,
+    /// The number of failed attempts to run the job.
+    #[serde(default)]
+    pub attempts: u32,
+    /// The error of the last failed attempt.
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// The time in nanoseconds since the epoch before which the job is not retried.
+    #[serde(default)]
+    pub next_attempt_at: u64,
+    /// The fees of the last result transaction.
+    #[serde(default)]
+    pub fees: Option<GasFees>,
+    /// The time in nanoseconds since the epoch at which the last result transaction
+    /// was sent.
+    #[serde(default)]
+    pub submitted_at: u64,
+    /// The result transactions that were replaced by a transaction with higher fees.
+    /// Any of them can still be mined instead of the replacement.
+    #[serde(default)]
+    pub replaced_tx_hashes: Vec<B256>,
+    /// The gas used by the mined result transaction.
+    #[serde(default)]
+    pub gas_used: Option<u128>,
+    /// The cycles spent on the job so far.
+    #[serde(default)]
+    pub cycles: CyclesSpent,
+}
+
+impl Job {
+    pub fn new(chain_id: u64, log: Log) -> Self {
+        Self {
+            chain_id,
+            log,
+            status: JobStatus::Pending,
+            job_id: None,
+            result: None,
+            nonce: None,
+            result_chain_id: None,
+            attempts: 0,
+            last_error: None,
+            next_attempt_at: 0,
+            fees: None,
+            submitted_at: 0,
+            replaced_tx_hashes: vec![],
+            gas_used: None,
+            cycles: Default::default(),
+        }
+    }
+}
+
+/// The EIP-1559 fees of a result transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GasFees {
+    pub max_fee_per_gas: u128,
+    pub max_priority_fee_per_gas: u128,
+}
+
+impl GasFees {
+    /// Raises both fees by `percent`, rounding up so that the replacement is not
+    /// rejected as underpriced.
+    pub fn bump(self, percent: u64) -> Self {
+        let bump = |fee: u128| fee.saturating_mul(100 + percent as u128).div_ceil(100);
+        Self {
+            max_fee_per_gas: bump(self.max_fee_per_gas),
+            max_priority_fee_per_gas: bump(self.max_priority_fee_per_gas),
+        }
+    }
+
+    pub fn max(self, other: Self) -> Self {
+        Self {
+            max_fee_per_gas: self.max_fee_per_gas.max(other.max_fee_per_gas),
+            max_priority_fee_per_gas: self
+                .max_priority_fee_per_gas
+                .max(other.max_priority_fee_per_gas),
+        }
+    }
+}
+
+/// The default number of jobs that are run concurrently.
+pub const DEFAULT_MAX_JOBS_IN_FLIGHT: u32 = 10;
+
+/// The smallest fee bump that nodes accept for a replacement transaction.
+pub const MIN_FEE_BUMP_PERCENT: u64 = 10;
+
+/// When result transactions that are not mined are replaced.
+#[derive(CandidType, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResubmissionPolicy {
+    /// How long a result transaction may stay unmined before it is replaced.
+    pub timeout_secs: u64,
+    /// By how many percent the fees of the replacement are raised, at least
+    /// `MIN_FEE_BUMP_PERCENT`.
+    pub fee_bump_percent: u64,
+}
+
+impl Default for ResubmissionPolicy {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 300,
+            fee_bump_percent: MIN_FEE_BUMP_PERCENT,
+        }
+    }
+}
+
+/// The price that a job has to pay with the transaction that emitted its log.
+#[derive(CandidType, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaymentPolicy {
+    /// The gas that the result transaction of a job needs.
+    pub gas_per_job: u64,
+    /// By how many percent the price exceeds the cost of `gas_per_job` at the current
+    /// max fee per gas, to cover fee rises until the result is submitted.
+    pub margin_percent: u64,
+}
+
+impl PaymentPolicy {
+    /// Returns the price of a job in wei at the given max fee per gas.
+    pub fn price(&self, max_fee_per_gas: u128) -> U256 {
+        U256::from(self.gas_per_job)
+            .saturating_mul(U256::from(max_fee_per_gas))
+            .saturating_mul(U256::from(100_u64.saturating_add(self.margin_percent)))
+            / U256::from(100)
+    }
+}
+
+/// How results reach the coprocessor contract of their result chain.
+#[derive(CandidType, Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResultSink {
+    /// The canister sends a result transaction and pays for its gas.
+    #[default]
+    Transaction,
+    /// The canister signs an EIP-712 attestation of the result, which anyone can
+    /// relay to the contract with `submitAttestedResult`, see `attestation::attest`.
+    Attestation,
+}
+
+/// When computed results are submitted together in one `callbackBatch` transaction.
+#[derive(CandidType, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchPolicy {
+    /// The most results in one batch. A full batch is submitted right away.
+    pub max_batch_size: u32,
+    /// How long results are collected before a batch that is not full is submitted.
+    pub window_secs: u64,
+}
+
+/// How often and how quickly failed jobs are retried.
+#[derive(CandidType, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// The number of attempts after which a job is moved to the dead letter jobs.
+    pub max_attempts: u32,
+    /// The delay before the first retry. It doubles with every further attempt.
+    pub initial_backoff_secs: u64,
+    /// The maximum delay between two attempts.
+    pub max_backoff_secs: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff_secs: 60,
+            max_backoff_secs: 3_600,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the delay before the next attempt after `attempts` failed attempts.
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(63);
+        let backoff_secs = self
+            .initial_backoff_secs
+            .saturating_mul(1_u64 << exponent)
+            .min(self.max_backoff_secs);
+        Duration::from_secs(backoff_secs)
+    }
+}
+
+/// The lifecycle of a job. Each status is written to the state before the next
+/// step is started, so that the scheduler can resume a job from its last durable step
+/// after an error or a trap.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobStatus {
+    /// The log was scraped but the job has not been started yet.
+    Pending,
+    /// The job is decoded by its handler, its result computed and submitted.
+    Computing,
+    /// The result is computed and waits to be submitted with the next batch, see
+    /// `BatchPolicy`.
+    Computed,
+    /// The result transaction was sent and is waiting to be mined, see
+    /// `monitor_transactions`.
+    Submitted { tx_hash: B256 },
+    /// The result transaction was mined and succeeded.
+    Confirmed { tx_hash: B256 },
+    /// The result transaction was mined but reverted, with the reason obtained by
+    /// replaying the call.
+    ExecutionReverted { tx_hash: B256, reason: String },
+    /// The job could not be completed.
+    Failed { reason: String },
+    /// The job was reverted by a reorg and compensated instead of run again.
+    Compensated,
+    /// There is no handler for the log's event.
+    Unhandled,
+    /// The transaction that created the job paid less than the `PaymentPolicy`
+    /// requires, both in wei.
+    Rejected { paid: U256, price: U256 },
+    /// The result was signed instead of sent, see `ResultSink::Attestation`.
+    Attested,
+}
+
+impl JobStatus {
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            JobStatus::Confirmed { .. }
+                | JobStatus::ExecutionReverted { .. }
+                | JobStatus::Failed { .. }
+                | JobStatus::Compensated
+                | JobStatus::Unhandled
+                | JobStatus::Rejected { .. }
+                | JobStatus::Attested
+        )
+    }
+}
+
+/// The number of scraped ranges whose last block hash is kept for reorg detection.
+const MAX_SCRAPED_BLOCK_HASHES: usize = 100;
+/// The largest power of two that the scraping interval is multiplied with after
+/// inconsistent scrapes.
+const MAX_INCONSISTENT_SCRAPES_BACKOFF: u32 = 6;
+
+/// A job that reached a final status. Unlike `Job`, it doesn't keep the full log so
+/// that the processed jobs in stable memory stay small.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessedJob {
+    pub chain_id: u64,
+    #[serde(default)]
+    pub result_chain_id: Option<u64>,
+    pub block_number: Option<u64>,
+    pub job_id: Option<U256>,
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    #[serde(default)]
+    pub gas_used: Option<u128>,
+    #[serde(default)]
+    pub cycles: CyclesSpent,
+    /// The time the job was processed in nanoseconds since the epoch.
+    pub processed_at: u64,
+    /// The position of the job in the order of processing, assigned by
+    /// `storage::record_processed_job`.
+    pub sequence: u64,
+}
+
+impl ProcessedJob {
+    pub fn new(job: Job, status: JobStatus, processed_at: u64) -> Self {
+        Self {
+            chain_id: job.chain_id,
+            result_chain_id: job.result_chain_id,
+            block_number: job.log.block_number,
+            job_id: job.job_id,
+            status,
+            attempts: job.attempts,
+            last_error: job.last_error,
+            gas_used: job.gas_used,
+            cycles: job.cycles,
+            processed_at,
+            sequence: 0,
+        }
+    }
+}
+
+/// How long processed jobs are kept. Processed jobs are only needed to deduplicate
+/// logs and for the job queries, so old ones are pruned to keep stable memory bounded.
+#[derive(CandidType, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// The maximum number of processed jobs to keep.
+    pub max_entries: Option<u64>,
+    /// The maximum age of processed jobs in seconds.
+    pub max_age_secs: Option<u64>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            max_entries: Some(10_000),
+            max_age_secs: None,
+        }
+    }
+}
+
+/// A log whose block is no longer part of the canonical chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevertedLog {
+    pub chain_id: u64,
+    pub block_number: Option<u64>,
+    /// Whether the job for this log had already been run when the log was reverted.
+    pub was_processed: bool,
+}
+
+/// Determines what happens to a processed job whose log is reverted by a reorg and
+/// then included in the canonical chain again.
+#[derive(CandidType, Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReorgPolicy {
+    /// Run the job again.
+    #[default]
+    Rerun,
+    /// Only run the job again if its result was lost with the reorg, see
+    /// `job::compensate`.
+    Compensate,
+}
+
+/// Determines up to which block logs are scraped.
+#[derive(CandidType, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConfirmationPolicy {
+    /// Scrape blocks that are at least this many blocks behind the latest block.
+    /// `Confirmations(0)` scrapes up to the latest block.
+    Confirmations(u64),
+    /// Scrape up to the block with the `safe` tag.
+    Safe,
+    /// Scrape up to the block with the `finalized` tag.
+    Finalized,
+}
+
+impl Default for ConfirmationPolicy {
+    fn default() -> Self {
+        Self::Confirmations(0)
+    }
 }
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum InvalidStateError {
     InvalidEthereumContractAddress(String),
+    InvalidRetryPolicy(String),
+    InvalidEventSignature(String),
+    InvalidScrapingInterval(String),
+    InvalidRetentionPolicy(String),
+    InvalidResubmissionPolicy(String),
+    InvalidScrapingBackend(String),
+    InvalidChain(String),
+    InvalidMaxJobsInFlight(String),
+    InvalidPaymentPolicy(String),
+    InvalidBatchPolicy(String),
 }
 
 impl State {
-    pub fn record_log_to_process(&mut self, log_entry: &Log) {
+    pub fn chain(&self, chain_id: u64) -> &ChainState {
+        match self.chains.get(&chain_id) {
+            Some(chain) => chain,
+            None => panic!("BUG: unknown chain {chain_id}"),
+        }
+    }
+
+    pub fn chain_mut(&mut self, chain_id: u64) -> &mut ChainState {
+        match self.chains.get_mut(&chain_id) {
+            Some(chain) => chain,
+            None => panic!("BUG: unknown chain {chain_id}"),
+        }
+    }
+
+    /// Returns the chain for a configuration change, which fails for unknown chains.
+    pub fn configured_chain_mut(
+        &mut self,
+        chain_id: u64,
+    ) -> Result<&mut ChainState, InvalidStateError> {
+        self.chains.get_mut(&chain_id).ok_or_else(|| {
+            InvalidStateError::InvalidChain(format!("ERROR: unknown chain {}", chain_id))
+        })
+    }
+
+    pub fn record_log_to_process(&mut self, chain_id: u64, log_entry: &Log) {
         let event_source = log_entry.source();
-        assert!(
-            !self.logs_to_process.contains_key(&event_source),
-            "there must be no two different events with the same source"
-        );
-        assert!(!self.processed_logs.contains_key(&event_source));
+        if log_entry.removed {
+            self.revert_log(&event_source);
+            return;
+        }
+        // A log is only ever scraped twice if its block was reorged out in between, in
+        // which case it is found in `reverted_logs`. Everything else is a duplicate.
+        if self.logs_to_process.contains_key(&event_source)
+            || storage::is_processed(&event_source)
+            || self.dead_letter_jobs.contains_key(&event_source)
+        {
+            return;
+        }
+
+        match self.reverted_logs.remove(&event_source) {
+            Some(RevertedLog {
+                was_processed: true,
+                ..
+            }) if self.reorg_policy == ReorgPolicy::Compensate => {
+                self.logs_to_compensate
+                    .insert(event_source, Job::new(chain_id, log_entry.clone()));
+            }
+            _ => {
+                self.logs_to_process
+                    .insert(event_source, Job::new(chain_id, log_entry.clone()));
+            }
+        }
+    }
+
+    /// Marks the log as reverted, removing it from the queue or the processed logs.
+    pub fn revert_log(&mut self, source: &LogSource) {
+        let reverted = if let Some(job) = self.logs_to_process.remove(source) {
+            self.release_nonce(&job);
+            RevertedLog {
+                chain_id: job.chain_id,
+                block_number: job.log.block_number,
+                was_processed: false,
+            }
+        } else if let Some(job) = storage::remove_processed_job(source) {
+            // the attestation is for a job that no longer exists, and its job id may be
+            // taken by another one
+            if let (JobStatus::Attested, Some(job_id)) = (&job.status, job.job_id) {
+                storage::remove_attestation(&AttestationKey {
+                    chain_id: job.result_chain_id.unwrap_or(job.chain_id),
+                    job_id,
+                });
+            }
+            RevertedLog {
+                chain_id: job.chain_id,
+                block_number: job.block_number,
+                was_processed: true,
+            }
+        } else if let Some(job) = self.dead_letter_jobs.remove(source) {
+            RevertedLog {
+                chain_id: job.chain_id,
+                block_number: job.log.block_number,
+                was_processed: false,
+            }
+        } else if let Some(job) = self.logs_to_compensate.remove(source) {
+            RevertedLog {
+                chain_id: job.chain_id,
+                block_number: job.log.block_number,
+                was_processed: true,
+            }
+        } else {
+            return;
+        };
+        self.reverted_logs.insert(source.clone(), reverted);
+    }
+
+    /// Records a configuration change, dropping the oldest entry once the audit log
+    /// holds `MAX_AUDIT_LOG_ENTRIES`.
+    pub fn record_audit_log_entry(&mut self, entry: AuditLogEntry) {
+        self.audit_log.push(entry);
+        if self.audit_log.len() > MAX_AUDIT_LOG_ENTRIES {
+            self.audit_log.remove(0);
+        }
+    }
+
+    pub fn record_alert(&mut self, message: String, now: u64) {
+        self.alerts.push(Alert {
+            timestamp: now,
+            message,
+        });
+        if self.alerts.len() > MAX_ALERTS {
+            self.alerts.remove(0);
+        }
+    }
+
+    /// Rolls the scraper of `chain_id` back to `block_number` after a reorg: all logs
+    /// of the chain from later blocks are reverted and those blocks are scraped again.
+    pub fn rollback_to(&mut self, chain_id: u64, block_number: u64) {
+        let mut reverted_sources: Vec<LogSource> = self
+            .logs_to_process
+            .iter()
+            .chain(self.dead_letter_jobs.iter())
+            .chain(self.logs_to_compensate.iter())
+            .filter(|(_, job)| {
+                job.chain_id == chain_id && job.log.block_number.is_some_and(|n| n > block_number)
+            })
+            .map(|(source, _)| source.clone())
+            .collect();
+        reverted_sources.extend(storage::processed_jobs_after_block(chain_id, block_number));
+        for source in reverted_sources.iter() {
+            self.revert_log(source);
+        }
+        let chain = self.chain_mut(chain_id);
+        chain.scraped_block_hashes.split_off(&(block_number + 1));
+        if chain.last_scraped_block > Some(block_number) {
+            chain.last_scraped_block = Some(block_number);
+        }
+    }
+
+    /// Applies `f` to the queued job for `source`.
+    pub fn update_job(&mut self, source: &LogSource, f: impl FnOnce(&mut Job)) {
+        match self.logs_to_process.get_mut(source) {
+            Some(job) => f(job),
+            None => panic!("attempted to update an unknown job {source:?}"),
+        }
+    }
+
+    /// Records a failed attempt to run the queued job for `source`. The job keeps
+    /// its status and is retried after the backoff of the retry policy, or moved to the
+    /// dead letter jobs if it has run out of attempts.
+    ///
+    /// A job only keeps its nonce through the backoff if its transaction may have been
+    /// sent, see `release_job_nonce`, as it has to be replaced with the same nonce.
+    pub fn record_job_failure(&mut self, source: LogSource, reason: String, now: u64) {
+        let mut job = match self.logs_to_process.remove(&source) {
+            Some(job) => job,
+            None => panic!("attempted to retry an unknown job {source:?}"),
+        };
+        job.attempts += 1;
+        job.last_error = Some(reason);
+
+        if job.attempts >= self.retry_policy.max_attempts {
+            self.release_nonce(&job);
+            self.dead_letter_jobs.insert(source, job);
+        } else {
+            let backoff = self.retry_policy.backoff(job.attempts);
+            job.next_attempt_at = now.saturating_add(backoff.as_nanos() as u64);
+            self.logs_to_process.insert(source, job);
+        }
+    }
+
+    /// Moves a dead letter job back to the queue with a fresh set of attempts.
+    pub fn retry_dead_letter_job(&mut self, source: &LogSource) -> Result<(), String> {
+        let mut job = self
+            .dead_letter_jobs
+            .remove(source)
+            .ok_or_else(|| format!("no dead letter job for {source:?}"))?;
+        job.attempts = 0;
+        job.next_attempt_at = 0;
+        // the released nonce may have been used to fill a gap in the meantime, so a job
+        // that has not sent its transaction yet reserves a new one
+        if !matches!(job.status, JobStatus::Submitted { .. }) {
+            job.nonce = None;
+            job.result_chain_id = None;
+        }
+        self.logs_to_process.insert(source.clone(), job);
+        Ok(())
+    }
+
+    /// Gives up the nonce of the queued job for `source` after its transaction failed
+    /// before it could have been sent, so that the nonce does not block later
+    /// transactions while the job backs off. The job reserves a new one when it is
+    /// retried.
+    pub fn release_job_nonce(&mut self, source: &LogSource) {
+        let job = self.logs_to_process[source].clone();
+        self.release_nonce(&job);
+        self.update_job(source, |job| {
+            job.nonce = None;
+            job.result_chain_id = None;
+        });
+    }
+
+    /// Records the nonce that the job for `source` reserved on `result_chain_id`. If
+    /// the job was reverted by a reorg while the nonce was reserved, the nonce is
+    /// released again and `false` is returned.
+    pub fn record_job_nonce(
+        &mut self,
+        source: &LogSource,
+        result_chain_id: u64,
+        nonce: u64,
+    ) -> bool {
+        match self.logs_to_process.get_mut(source) {
+            Some(job) => {
+                job.result_chain_id = Some(result_chain_id);
+                job.nonce = Some(nonce);
+                true
+            }
+            None => {
+                self.chain_mut(result_chain_id).nonces.release(nonce);
+                false
+            }
+        }
+    }
+
+    /// Records that the result transaction `tx_hash` of the job for `source` was
+    /// sent. The transaction is tracked by the nonce manager even if the job was
+    /// reverted by a reorg while it was sent, as it uses up the nonce anyway.
+    pub fn record_job_sent(
+        &mut self,
+        source: &LogSource,
+        result_chain_id: u64,
+        nonce: u64,
+        tx_hash: B256,
+        fees: GasFees,
+        now: u64,
+    ) {
+        let chain = self.chain_mut(result_chain_id);
+        chain.nonces.record_sent(nonce, tx_hash);
+        chain.spend_gas_balance(1);
+        if let Some(job) = self.logs_to_process.get_mut(source) {
+            job.status = JobStatus::Submitted { tx_hash };
+            job.fees = Some(fees);
+            job.submitted_at = now;
+        }
+    }
 
-        self.logs_to_process.insert(event_source, log_entry.clone());
+    /// Gives the nonce reserved by `job` back to the nonce manager, for jobs that
+    /// leave the queue without a final status.
+    pub fn release_nonce(&mut self, job: &Job) {
+        if let (Some(nonce), Some(result_chain_id)) = (job.nonce, job.result_chain_id) {
+            if let Some(chain) = self.chains.get_mut(&result_chain_id) {
+                chain.nonces.release(nonce);
+            }
+        }
     }
 
-    pub fn record_processed_log(&mut self, source: LogSource) {
-        let log_entry = match self.logs_to_process.remove(&source) {
-            Some(event) => event,
+    /// Sets the final `status` of the queued job for `source` and moves it to the
+    /// processed jobs in stable memory.
+    pub fn record_processed_log(&mut self, source: LogSource, status: JobStatus) {
+        assert!(status.is_final(), "{status:?} is not a final job status");
+        let job = match self.logs_to_process.remove(&source) {
+            Some(job) => job,
             None => panic!("attempted to run job for an unknown event {source:?}"),
         };
+        // a job that fails after its nonce was reserved, e.g. because its result call
+        // reverts, leaves a gap
+        self.release_nonce(&job);
 
-        assert_eq!(
-            self.processed_logs.insert(source.clone(), log_entry),
-            None,
+        assert!(
+            storage::record_processed_job(
+                source.clone(),
+                ProcessedJob::new(job, status, ic_cdk::api::time()),
+                &self.retention_policy,
+                &self.rescannable_from(),
+            ),
             "attempted to run job twice for the same event {source:?}"
         );
     }
 
-    pub fn has_logs_to_process(&self) -> bool {
-        !self.logs_to_process.is_empty()
+    /// Returns the first block of every chain that a reorg can still make the scraper
+    /// scan again: `logs::detect_reorg` rolls back at most to the block before the
+    /// oldest block whose hash is tracked. The processed jobs of these blocks are
+    /// needed to deduplicate their logs and are never pruned.
+    pub fn rescannable_from(&self) -> BTreeMap<u64, u64> {
+        self.chains
+            .values()
+            .filter_map(|chain| {
+                let (oldest_block, _) = chain.scraped_block_hashes.first_key_value()?;
+                Some((chain.chain_id, *oldest_block))
+            })
+            .collect()
     }
 
-    pub fn key_id(&self) -> EcdsaKeyId {
-        self.ecdsa_key_id.clone()
+    /// Queues a job to compensate to run again, as if it had never been processed.
+    pub fn requeue_compensated_log(&mut self, source: LogSource) {
+        let job = match self.logs_to_compensate.remove(&source) {
+            Some(job) => job,
+            None => panic!("attempted to requeue an unknown event {source:?}"),
+        };
+        self.logs_to_process
+            .insert(source, Job::new(job.chain_id, job.log));
+    }
+
+    pub fn record_compensated_log(&mut self, source: LogSource) {
+        let job = match self.logs_to_compensate.remove(&source) {
+            Some(job) => job,
+            None => panic!("attempted to compensate an unknown event {source:?}"),
+        };
+        storage::record_processed_job(
+            source,
+            ProcessedJob::new(job, JobStatus::Compensated, ic_cdk::api::time()),
+            &self.retention_policy,
+            &self.rescannable_from(),
+        );
+    }
+
+    /// Picks the queued jobs to start next, so that at most `max_jobs_in_flight` jobs
+    /// are running, and records them as running. The nonces of their result
+    /// transactions are reserved by the jobs right before they are signed, so that jobs
+    /// that are rejected, fail or back off in the meantime do not leave gaps.
+    pub fn start_jobs(&mut self, now: u64) -> Vec<LogSource> {
+        // jobs that trapped after an `await` never release their slot
+        self.jobs_in_flight
+            .retain(|_, started_at| !guard::is_stale(*started_at, JOB_GUARD_LEASE, now));
+        let capacity = (self.max_jobs_in_flight as usize).saturating_sub(self.jobs_in_flight.len());
+        // the gas balance has to cover the result transactions of the running jobs as
+        // well as those of the jobs started here
+        let mut callbacks: BTreeMap<u64, u64> = BTreeMap::new();
+        for source in self.jobs_in_flight.keys() {
+            if let Some(chain) = self
+                .logs_to_process
+                .get(source)
+                .and_then(|job| self.gas_chain(job))
+            {
+                *callbacks.entry(chain.chain_id).or_default() += 1;
+            }
+        }
+        let mut sources: Vec<LogSource> = vec![];
+        for (source, job) in self.logs_to_process.iter() {
+            if sources.len() >= capacity {
+                break;
+            }
+            if self.jobs_in_flight.contains_key(source)
+                || job.next_attempt_at > now
+                || matches!(job.status, JobStatus::Submitted { .. })
+                || (job.status == JobStatus::Computed
+                    && self.batch_policy.is_some()
+                    && self.sends_result_transaction(job))
+            {
+                continue;
+            }
+            if let Some(chain) = self.gas_chain(job) {
+                let chain_callbacks = callbacks.entry(chain.chain_id).or_default();
+                if !chain.has_gas_for_callbacks(*chain_callbacks + 1) {
+                    continue;
+                }
+                *chain_callbacks += 1;
+            }
+            sources.push(source.clone());
+        }
+
+        for source in sources.iter() {
+            self.jobs_in_flight.insert(source.clone(), now);
+        }
+        sources
+    }
+
+    /// Returns the jobs whose results wait to be submitted in a batch, by the chain
+    /// that they are posted to.
+    pub fn computed_jobs(&self) -> BTreeMap<u64, Vec<LogSource>> {
+        let mut computed_jobs: BTreeMap<u64, Vec<LogSource>> = BTreeMap::new();
+        for (source, job) in self.logs_to_process.iter() {
+            // results that are attested by now are left to `start_jobs`
+            if job.status != JobStatus::Computed || !self.sends_result_transaction(job) {
+                continue;
+            }
+            if let Some(result_chain_id) = self.job_result_chain_id(job) {
+                computed_jobs
+                    .entry(result_chain_id)
+                    .or_default()
+                    .push(source.clone());
+            }
+        }
+        computed_jobs
+    }
+
+    /// Groups the computed jobs that are due into the batches to submit, by the chain
+    /// that their results are posted to. The jobs of a batch whose transaction may
+    /// have been sent keep its nonce and are sent together again, the others are
+    /// batched anew in batches of at most `max_batch_size` jobs. The results of chains
+    /// whose gas cannot be paid wait in the queue.
+    pub fn batches(&self, max_batch_size: u32, now: u64) -> Vec<(u64, Vec<LogSource>)> {
+        let mut batches = vec![];
+        for (result_chain_id, sources) in self.computed_jobs() {
+            if !self.chain(result_chain_id).has_gas_for_callbacks(1) {
+                continue;
+            }
+            let mut by_nonce: BTreeMap<Option<u64>, Vec<LogSource>> = BTreeMap::new();
+            for source in sources {
+                let job = &self.logs_to_process[&source];
+                if job.next_attempt_at <= now {
+                    by_nonce.entry(job.nonce).or_default().push(source);
+                }
+            }
+            for (nonce, sources) in by_nonce {
+                let batch_size = match nonce {
+                    Some(_) => sources.len(),
+                    None => max_batch_size as usize,
+                };
+                batches.extend(
+                    sources
+                        .chunks(batch_size)
+                        .map(|batch| (result_chain_id, batch.to_vec())),
+                );
+            }
+        }
+        batches
+    }
+
+    /// Returns the chain that the result of `job` is posted to.
+    pub fn job_result_chain_id(&self, job: &Job) -> Option<u64> {
+        job.result_chain_id.or_else(|| {
+            self.chains
+                .get(&job.chain_id)
+                .map(ChainState::result_chain_id)
+        })
+    }
+
+    /// Returns the chain whose gas pays for the result transaction of `job`, if the job
+    /// has not submitted it yet. Such jobs wait in the queue while the canister's EVM
+    /// address cannot pay for it, instead of failing with insufficient funds.
+    fn gas_chain(&self, job: &Job) -> Option<&ChainState> {
+        if !matches!(
+            job.status,
+            JobStatus::Pending | JobStatus::Computing | JobStatus::Computed
+        ) {
+            return None;
+        }
+        self.job_result_chain_id(job)
+            .and_then(|chain_id| self.chains.get(&chain_id))
+            .filter(|chain| chain.result_sink == ResultSink::Transaction)
+    }
+
+    /// Whether the result of `job` is posted with a result transaction rather than
+    /// attested.
+    pub fn sends_result_transaction(&self, job: &Job) -> bool {
+        match self
+            .job_result_chain_id(job)
+            .and_then(|chain_id| self.chains.get(&chain_id))
+        {
+            Some(chain) => chain.result_sink == ResultSink::Transaction,
+            None => true,
+        }
+    }
+
+    /// Records the balance of the canister's EVM address on `chain_id` and raises an
+    /// alert when it no longer covers the next result transaction.
+    pub fn record_gas_balance(&mut self, chain_id: u64, gas_balance: GasBalance) {
+        if self.chain(chain_id).has_gas_for_callbacks(1) && !gas_balance.covers(1) {
+            self.record_alert(
+                format!(
+                    "the balance of {} wei on chain {} does not cover the estimated cost of {} wei of the next result transaction, jobs stay queued until it is topped up",
+                    gas_balance.balance, chain_id, gas_balance.callback_cost
+                ),
+                gas_balance.checked_at,
+            );
+        }
+        self.chain_mut(chain_id).gas_balance = Some(gas_balance);
+    }
+
+    /// Adds `cycles` to the cycles spent by the canister and, if it is still queued,
+    /// by the job for `source`.
+    pub fn record_job_cycles(&mut self, source: &LogSource, cycles: CyclesSpent) {
+        if let Some(job) = self.logs_to_process.get_mut(source) {
+            job.cycles += cycles;
+        }
+        self.cycles_spent += cycles;
     }
 
-    pub fn get_filter_addresses(&self) -> Vec<Address> {
-        self.filter_addresses.clone()
+    pub fn has_logs_to_process(&self) -> bool {
+        !self.logs_to_process.is_empty() || !self.logs_to_compensate.is_empty()
     }
 
-    pub fn get_filter_events(&self) -> Vec<String> {
-        self.filter_events.clone()
+    pub fn key_id(&self) -> EcdsaKeyId {
+        self.ecdsa_key_id.clone()
     }
 }
 
-trait IntoLogSource {
+pub trait IntoLogSource {
     fn source(&self) -> LogSource;
 }
 
@@ -94,7 +1213,7 @@ impl IntoLogSource for Log {
 
 /// A unique identifier of the event source: the source transaction hash and the log
 /// entry index.
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct LogSource {
     pub transaction_hash: FixedBytes<32>,
     pub log_index: u64,
@@ -119,8 +1238,444 @@ pub fn initialize_state(state: State) {
     STATE.set(Some(state));
 }
 
-#[derive(Debug, Hash, Copy, Clone, PartialEq, Eq)]
+#[derive(CandidType, Deserialize, Debug, Hash, Copy, Clone, PartialEq, Eq)]
 pub enum TaskType {
     ProcessLogs,
     ScrapeLogs,
+    MonitorTransactions,
+    CheckGasBalances,
+    SubmitBatches,
+}
+
+// The defaults of the fields that a saved state may lack, see
+// `storage::STATE_VERSION`.
+fn default_max_jobs_in_flight() -> u32 {
+    DEFAULT_MAX_JOBS_IN_FLIGHT
+}
+
+fn default_initial_scraping_delay_secs() -> u64 {
+    crate::INITIAL_SCRAPING_DELAY.as_secs()
+}
+
+fn default_block_range() -> u64 {
+    crate::logs::MAX_BLOCK_RANGE
+}
+
+fn default_scraping_interval_secs() -> u64 {
+    crate::SCRAPING_LOGS_INTERVAL.as_secs()
+}
+
+/// (De)serializes types that only implement Candid, such as `RpcService`, through
+/// their Candid encoding.
+mod candid_encoded {
+    use candid::CandidType;
+    use serde::de::{DeserializeOwned, Error as _};
+    use serde::ser::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<T: CandidType, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let bytes = candid::encode_one(value).map_err(S::Error::custom)?;
+        serializer.serialize_bytes(&bytes)
+    }
+
+    pub fn deserialize<'de, T: CandidType + DeserializeOwned, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        let bytes = serde_bytes::ByteBuf::deserialize(deserializer)?;
+        candid::decode_one(&bytes).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::endpoints;
+    use crate::lifecycle::InitArg;
+    use crate::nonce::NonceStatus;
+    use alloy::transports::icp::RpcApi;
+    use ic_cdk::api::management_canister::ecdsa::EcdsaCurve;
+
+    const CHAIN_ID: u64 = 31337;
+
+    fn state() -> State {
+        let mut state = State::try_from(InitArg {
+            rpc_service: RpcService::Custom(RpcApi {
+                url: "http://localhost:8545".to_string(),
+                headers: None,
+            }),
+            chain_id: CHAIN_ID,
+            filter_addresses: vec![Address::repeat_byte(0xc0).to_string()],
+            coprocessor_evm_address: Address::repeat_byte(0xc0).to_string(),
+            filter_events: vec!["NewJob(uint256,address,uint256)".to_string()],
+            ecdsa_key_id: EcdsaKeyId {
+                curve: EcdsaCurve::Secp256k1,
+                name: "dfx_test_key".to_string(),
+            },
+            start_block: None,
+            confirmation_policy: None,
+            reorg_policy: None,
+            retry_policy: None,
+            retention_policy: None,
+            resubmission_policy: None,
+            scraping_backend: None,
+            scraping_cadence: None,
+            initial_scraping_delay_secs: None,
+            max_jobs_in_flight: None,
+            min_cycles_balance: None,
+            payment_policy: None,
+            batch_policy: None,
+            result_chain_id: None,
+            result_sink: None,
+            chains: None,
+        })
+        .unwrap();
+        state.chain_mut(CHAIN_ID).nonces.reconcile(0, 0);
+        state
+    }
+
+    fn log(block_number: u64, tx_hash: u8) -> Log {
+        Log {
+            block_number: Some(block_number),
+            transaction_hash: Some(B256::repeat_byte(tx_hash)),
+            log_index: Some(0),
+            ..Default::default()
+        }
+    }
+
+    /// Queues the job for `log` and starts it, like `job::start_jobs`.
+    fn start_job(state: &mut State, log: &Log) -> LogSource {
+        state.record_log_to_process(CHAIN_ID, log);
+        let started = state.start_jobs(0);
+        assert!(started.contains(&log.source()));
+        log.source()
+    }
+
+    fn nonce_owner(state: &State, nonce: u64) -> Option<endpoints::LogSource> {
+        NonceStatus::new(CHAIN_ID, &state.chain(CHAIN_ID).nonces)
+            .in_flight
+            .into_iter()
+            .find(|tx| tx.nonce == nonce)
+            .and_then(|tx| tx.log_source)
+    }
+
+    #[test]
+    fn should_keep_fixed_scraping_interval() {
+        let cadence = ScrapingCadence::Fixed { interval_secs: 12 };
+        assert_eq!(cadence.initial_interval_secs(), 12);
+        assert_eq!(cadence.next_interval_secs(12, false), 12);
+        assert_eq!(cadence.next_interval_secs(12, true), 12);
+    }
+
+    #[test]
+    fn should_double_adaptive_scraping_interval_without_logs() {
+        let cadence = ScrapingCadence::Adaptive {
+            min_interval_secs: 10,
+            max_interval_secs: 60,
+        };
+        assert_eq!(cadence.initial_interval_secs(), 10);
+        assert_eq!(cadence.next_interval_secs(10, false), 20);
+        assert_eq!(cadence.next_interval_secs(20, false), 40);
+        assert_eq!(cadence.next_interval_secs(40, false), 60);
+        assert_eq!(cadence.next_interval_secs(60, false), 60);
+        assert_eq!(cadence.next_interval_secs(u64::MAX, false), 60);
+    }
+
+    #[test]
+    fn should_reset_adaptive_scraping_interval_when_logs_are_found() {
+        let cadence = ScrapingCadence::Adaptive {
+            min_interval_secs: 10,
+            max_interval_secs: 60,
+        };
+        assert_eq!(cadence.next_interval_secs(60, true), 10);
+        // an interval below the minimum, e.g. from a previous cadence, is raised
+        assert_eq!(cadence.next_interval_secs(1, false), 10);
+    }
+
+    #[test]
+    fn should_price_jobs_with_margin() {
+        let policy = PaymentPolicy {
+            gas_per_job: 100_000,
+            margin_percent: 20,
+        };
+        assert_eq!(
+            policy.price(10_000_000_000),
+            U256::from(1_200_000_000_000_000_u64)
+        );
+        assert_eq!(policy.price(0), U256::ZERO);
+
+        let policy = PaymentPolicy {
+            gas_per_job: 100_000,
+            margin_percent: 0,
+        };
+        assert_eq!(policy.price(3), U256::from(300_000));
+    }
+
+    #[test]
+    fn should_saturate_the_price() {
+        let policy = PaymentPolicy {
+            gas_per_job: u64::MAX,
+            margin_percent: u64::MAX,
+        };
+        assert_eq!(policy.price(u128::MAX), U256::MAX / U256::from(100));
+    }
+
+    #[test]
+    fn should_double_the_backoff_up_to_the_max() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(0), Duration::from_secs(60));
+        assert_eq!(policy.backoff(1), Duration::from_secs(60));
+        assert_eq!(policy.backoff(2), Duration::from_secs(120));
+        assert_eq!(policy.backoff(3), Duration::from_secs(240));
+        assert_eq!(policy.backoff(6), Duration::from_secs(1_920));
+        assert_eq!(policy.backoff(7), Duration::from_secs(3_600));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(3_600));
+    }
+
+    #[test]
+    fn should_release_the_nonce_reserved_by_a_reverted_job() {
+        let mut state = state();
+        let source = start_job(&mut state, &log(1, 1));
+        let nonce = state
+            .chain_mut(CHAIN_ID)
+            .nonces
+            .reserve(source.clone())
+            .unwrap();
+
+        state.revert_log(&source);
+
+        assert!(!state.logs_to_process.contains_key(&source));
+        assert!(state.reverted_logs.contains_key(&source));
+        assert!(!state.record_job_nonce(&source, CHAIN_ID, nonce));
+        assert_eq!(nonce_owner(&state, nonce), None);
+        assert!(!state.logs_to_process.contains_key(&source));
+    }
+
+    #[test]
+    fn should_track_the_transaction_of_a_reverted_job() {
+        let mut state = state();
+        let source = start_job(&mut state, &log(1, 1));
+        let nonce = state
+            .chain_mut(CHAIN_ID)
+            .nonces
+            .reserve(source.clone())
+            .unwrap();
+        assert!(state.record_job_nonce(&source, CHAIN_ID, nonce));
+        assert_eq!(
+            nonce_owner(&state, nonce),
+            Some(endpoints::LogSource::from(&source))
+        );
+
+        state.revert_log(&source);
+        let fees = GasFees {
+            max_fee_per_gas: 2,
+            max_priority_fee_per_gas: 1,
+        };
+        state.record_job_sent(&source, CHAIN_ID, nonce, B256::repeat_byte(0xaa), fees, 0);
+
+        assert!(!state.logs_to_process.contains_key(&source));
+        let status = NonceStatus::new(CHAIN_ID, &state.chain(CHAIN_ID).nonces);
+        let tx = status
+            .in_flight
+            .iter()
+            .find(|tx| tx.nonce == nonce)
+            .unwrap();
+        assert_eq!(tx.tx_hash, Some(B256::repeat_byte(0xaa).to_string()));
+        assert!(tx.log_source.is_none());
+    }
+
+    #[test]
+    fn should_record_the_transaction_of_a_queued_job() {
+        let mut state = state();
+        let source = start_job(&mut state, &log(1, 1));
+        let nonce = state
+            .chain_mut(CHAIN_ID)
+            .nonces
+            .reserve(source.clone())
+            .unwrap();
+        assert!(state.record_job_nonce(&source, CHAIN_ID, nonce));
+        let fees = GasFees {
+            max_fee_per_gas: 2,
+            max_priority_fee_per_gas: 1,
+        };
+
+        state.record_job_sent(&source, CHAIN_ID, nonce, B256::repeat_byte(0xaa), fees, 42);
+
+        let job = &state.logs_to_process[&source];
+        assert_eq!(job.nonce, Some(nonce));
+        assert_eq!(
+            job.status,
+            JobStatus::Submitted {
+                tx_hash: B256::repeat_byte(0xaa)
+            }
+        );
+        assert_eq!(job.submitted_at, 42);
+    }
+
+    /// Records the job for `log` as processed, like `record_processed_log` without
+    /// taking the time from the IC.
+    fn record_processed(state: &State, log: &Log) {
+        storage::record_processed_job(
+            log.source(),
+            ProcessedJob::new(Job::new(CHAIN_ID, log.clone()), JobStatus::Unhandled, 0),
+            &state.retention_policy,
+            &state.rescannable_from(),
+        );
+    }
+
+    fn removed(log: &Log) -> Log {
+        Log {
+            removed: true,
+            ..log.clone()
+        }
+    }
+
+    #[test]
+    fn should_revert_and_requeue_a_removed_log() {
+        let mut state = state();
+        let log = log(1, 1);
+        state.record_log_to_process(CHAIN_ID, &log);
+
+        state.record_log_to_process(CHAIN_ID, &removed(&log));
+
+        assert!(!state.logs_to_process.contains_key(&log.source()));
+        assert!(!state.reverted_logs[&log.source()].was_processed);
+
+        state.record_log_to_process(CHAIN_ID, &log);
+
+        assert_eq!(
+            state.logs_to_process[&log.source()].status,
+            JobStatus::Pending
+        );
+        assert!(!state.reverted_logs.contains_key(&log.source()));
+    }
+
+    #[test]
+    fn should_rerun_a_processed_job_whose_log_is_reemitted() {
+        let mut state = state();
+        let log = log(1, 1);
+        record_processed(&state, &log);
+        // a log that is scraped again without a reorg is a duplicate
+        state.record_log_to_process(CHAIN_ID, &log);
+        assert!(state.logs_to_process.is_empty());
+
+        state.record_log_to_process(CHAIN_ID, &removed(&log));
+
+        assert!(!storage::is_processed(&log.source()));
+        assert!(state.reverted_logs[&log.source()].was_processed);
+
+        state.record_log_to_process(CHAIN_ID, &log);
+
+        assert!(state.logs_to_process.contains_key(&log.source()));
+        assert!(state.logs_to_compensate.is_empty());
+    }
+
+    #[test]
+    fn should_compensate_a_processed_job_whose_log_is_reemitted() {
+        let mut state = state();
+        state.reorg_policy = ReorgPolicy::Compensate;
+        let log = log(1, 1);
+        record_processed(&state, &log);
+        state.record_log_to_process(CHAIN_ID, &removed(&log));
+
+        state.record_log_to_process(CHAIN_ID, &log);
+
+        assert!(state.logs_to_process.is_empty());
+        assert!(state.logs_to_compensate.contains_key(&log.source()));
+
+        // the result was lost with the reorg
+        state.requeue_compensated_log(log.source());
+
+        assert!(state.logs_to_compensate.is_empty());
+        assert_eq!(
+            state.logs_to_process[&log.source()].status,
+            JobStatus::Pending
+        );
+    }
+
+    #[test]
+    fn should_roll_back_to_the_last_canonical_block() {
+        let mut state = state();
+        let chain = state.chain_mut(CHAIN_ID);
+        for block_number in 1..=3 {
+            chain
+                .scraped_block_hashes
+                .insert(block_number, B256::repeat_byte(block_number as u8));
+        }
+        chain.last_scraped_block = Some(3);
+        state.record_log_to_process(CHAIN_ID, &log(1, 1));
+        state.record_log_to_process(CHAIN_ID, &log(2, 2));
+        record_processed(&state, &log(3, 3));
+
+        state.rollback_to(CHAIN_ID, 1);
+
+        assert!(state.logs_to_process.contains_key(&log(1, 1).source()));
+        assert!(!state.reverted_logs.contains_key(&log(1, 1).source()));
+        assert!(!state.logs_to_process.contains_key(&log(2, 2).source()));
+        assert!(!state.reverted_logs[&log(2, 2).source()].was_processed);
+        assert!(!storage::is_processed(&log(3, 3).source()));
+        assert!(state.reverted_logs[&log(3, 3).source()].was_processed);
+        let chain = state.chain(CHAIN_ID);
+        assert_eq!(
+            chain
+                .scraped_block_hashes
+                .keys()
+                .copied()
+                .collect::<Vec<_>>(),
+            vec![1]
+        );
+        assert_eq!(chain.last_scraped_block, Some(1));
+    }
+
+    /// Queues a job whose result waits to be submitted with a batch.
+    fn computed(state: &mut State, tx_hash: u8, nonce: Option<u64>) -> LogSource {
+        let log = log(1, tx_hash);
+        let mut job = Job::new(CHAIN_ID, log.clone());
+        job.status = JobStatus::Computed;
+        job.job_id = Some(U256::from(tx_hash));
+        job.result = Some("6765".to_string());
+        job.nonce = nonce;
+        job.result_chain_id = nonce.map(|_| CHAIN_ID);
+        state.logs_to_process.insert(log.source(), job);
+        log.source()
+    }
+
+    #[test]
+    fn should_group_computed_jobs_into_batches() {
+        let mut state = state();
+        let fresh: Vec<LogSource> = (1..=5).map(|i| computed(&mut state, i, None)).collect();
+        // the jobs of a batch whose transaction may have been sent keep their nonce
+        let sent: Vec<LogSource> = (6..=8).map(|i| computed(&mut state, i, Some(7))).collect();
+        let backing_off = computed(&mut state, 9, None);
+        state.update_job(&backing_off, |job| job.next_attempt_at = 100);
+        state.record_log_to_process(CHAIN_ID, &log(1, 10));
+
+        assert_eq!(
+            state.batches(2, 50),
+            vec![
+                (CHAIN_ID, fresh[0..2].to_vec()),
+                (CHAIN_ID, fresh[2..4].to_vec()),
+                (CHAIN_ID, fresh[4..5].to_vec()),
+                (CHAIN_ID, sent.clone()),
+            ]
+        );
+        assert!(state
+            .batches(10, 100)
+            .contains(&(CHAIN_ID, [fresh, vec![backing_off]].concat())));
+    }
+
+    #[test]
+    fn should_hold_back_batches_whose_gas_cannot_be_paid() {
+        let mut state = state();
+        computed(&mut state, 1, None);
+        state.chain_mut(CHAIN_ID).gas_balance = Some(GasBalance {
+            balance: U256::ZERO,
+            callback_cost: U256::from(1),
+            checked_at: 0,
+        });
+
+        assert!(state.batches(2, 0).is_empty());
+    }
 }
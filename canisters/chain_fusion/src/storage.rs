@@ -1,14 +1,26 @@
+use crate::attestation::{Attestation, AttestationKey};
+use crate::state::{LogSource, ProcessedJob, RetentionPolicy, State};
+use alloy::primitives::{hex, FixedBytes, U256};
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     storable::Bound,
     storable::Storable,
-    DefaultMemoryImpl, StableBTreeMap,
+    writer::Writer,
+    DefaultMemoryImpl, Memory, StableBTreeMap,
 };
 use minicbor_derive::{Decode, Encode};
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 
 const ASSETS_MEMORY_ID: MemoryId = MemoryId::new(0);
+const UPGRADES_MEMORY_ID: MemoryId = MemoryId::new(1);
+const PROCESSED_JOBS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const PROCESSED_JOBS_INDEX_MEMORY_ID: MemoryId = MemoryId::new(3);
+const ATTESTATIONS_MEMORY_ID: MemoryId = MemoryId::new(4);
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const WASM_PAGE_SIZE: u64 = 65536;
 
 type VMem = VirtualMemory<DefaultMemoryImpl>;
 
@@ -40,6 +52,88 @@ impl Storable for Asset {
     const BOUND: Bound = Bound::Unbounded;
 }
 
+impl Storable for LogSource {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(40);
+        buf.extend_from_slice(self.transaction_hash.as_slice());
+        buf.extend_from_slice(&self.log_index.to_be_bytes());
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (hash, index) = bytes.split_at(32);
+        Self {
+            transaction_hash: FixedBytes::from_slice(hash),
+            log_index: u64::from_be_bytes(index.try_into().expect("log index should be 8 bytes")),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 40,
+        is_fixed_size: true,
+    };
+}
+
+impl Storable for ProcessedJob {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = vec![];
+        ciborium::ser::into_writer(self, &mut buf).expect("failed to encode processed job");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        ciborium::de::from_reader(bytes.as_ref()).unwrap_or_else(|e| {
+            panic!(
+                "failed to decode processed job bytes {}: {e}",
+                hex::encode(bytes)
+            )
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for AttestationKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(40);
+        buf.extend_from_slice(&self.chain_id.to_be_bytes());
+        buf.extend_from_slice(&self.job_id.to_be_bytes::<32>());
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (chain_id, job_id) = bytes.split_at(8);
+        Self {
+            chain_id: u64::from_be_bytes(chain_id.try_into().expect("chain id should be 8 bytes")),
+            job_id: U256::from_be_slice(job_id),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 40,
+        is_fixed_size: true,
+    };
+}
+
+impl Storable for Attestation {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = vec![];
+        ciborium::ser::into_writer(self, &mut buf).expect("failed to encode attestation");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        ciborium::de::from_reader(bytes.as_ref()).unwrap_or_else(|e| {
+            panic!(
+                "failed to decode attestation bytes {}: {e}",
+                hex::encode(bytes)
+            )
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
         MemoryManager::init(DefaultMemoryImpl::default())
@@ -53,9 +147,21 @@ thread_local! {
                 )
             )
     );
+    // Jobs that reached a final status, keyed by the log that triggered them.
+    static PROCESSED_JOBS: RefCell<StableBTreeMap<LogSource, ProcessedJob, VMem>> = MEMORY_MANAGER
+        .with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(PROCESSED_JOBS_MEMORY_ID))));
+    // The processed jobs in the order they were processed, keyed by their sequence number.
+    static PROCESSED_JOBS_INDEX: RefCell<StableBTreeMap<u64, LogSource, VMem>> = MEMORY_MANAGER
+        .with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(PROCESSED_JOBS_INDEX_MEMORY_ID))));
+    // Signed results of jobs whose result chain uses `ResultSink::Attestation`, keyed
+    // by the chain and the job id. Unlike processed jobs they are not pruned, as
+    // users may relay them at any time.
+    static ATTESTATIONS: RefCell<StableBTreeMap<AttestationKey, Attestation, VMem>> = MEMORY_MANAGER
+        .with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(ATTESTATIONS_MEMORY_ID))));
 }
 
 /// Stores the asset in the stable memory.
+#[allow(dead_code)]
 pub fn store_asset(path: String, asset: Asset) {
     ASSETS.with(|assets| assets.borrow_mut().insert(path, asset));
 }
@@ -63,6 +169,307 @@ pub fn store_asset(path: String, asset: Asset) {
 /// Gets an assset from stable memory.
 /// Returns `None` if the asset is not found.
 /// Returns `Some(asset)` if the asset is found.
+#[allow(dead_code)]
 pub fn get_asset(path: &String) -> Option<Asset> {
     ASSETS.with(|assets| assets.borrow().get(path))
 }
+
+fn upgrades_memory() -> VMem {
+    MEMORY_MANAGER.with(|m| m.borrow().get(UPGRADES_MEMORY_ID))
+}
+
+/// The version of the layout written by `save_state`, which precedes the encoded
+/// state. Fields added to `State` later need a `#[serde(default)]`, changes that
+/// cannot be decoded that way need a new version and a migration in `decode_state`.
+const STATE_VERSION: u8 = 1;
+
+/// Serializes the state into the upgrades memory.
+/// The encoded state is prefixed with its length and `STATE_VERSION` so that it can
+/// be read back in `post_upgrade`.
+pub fn save_state(state: &State) {
+    let mut bytes = vec![STATE_VERSION];
+    ciborium::ser::into_writer(state, &mut bytes).expect("failed to encode state");
+    let mut memory = upgrades_memory();
+    let mut writer = Writer::new(&mut memory, 0);
+    writer
+        .write(&(bytes.len() as u64).to_le_bytes())
+        .expect("failed to write state length to stable memory");
+    writer
+        .write(&bytes)
+        .expect("failed to write state to stable memory");
+}
+
+/// Deserializes the state previously written by `save_state`. Returns `Ok(None)` if
+/// no state was saved, which is the case when upgrading from a version that did not
+/// persist its state.
+pub fn load_state() -> Result<Option<State>, String> {
+    let memory = upgrades_memory();
+    if memory.size() == 0 {
+        return Ok(None);
+    }
+    let mut len_bytes = [0; 8];
+    memory.read(0, &mut len_bytes);
+    let len = u64::from_le_bytes(len_bytes);
+    if len == 0 {
+        return Ok(None);
+    }
+    if len > memory.size() * WASM_PAGE_SIZE - len_bytes.len() as u64 {
+        return Err(format!(
+            "the saved state is {len} bytes long, which exceeds the upgrades memory"
+        ));
+    }
+    let mut bytes = vec![0; len as usize];
+    memory.read(len_bytes.len() as u64, &mut bytes);
+    decode_state(&bytes).map(Some)
+}
+
+/// Decodes a state written by `save_state`.
+fn decode_state(bytes: &[u8]) -> Result<State, String> {
+    match bytes.first() {
+        Some(&STATE_VERSION) => ciborium::de::from_reader(&bytes[1..])
+            .map_err(|e| format!("failed to decode the saved state: {e}")),
+        Some(version) => Err(format!("unknown saved state version {version}")),
+        None => Err("the saved state is empty".to_string()),
+    }
+}
+
+/// Records a processed job and prunes the processed jobs that fall outside of the
+/// retention policy, except for those in blocks from `rescannable_from` on, see
+/// `State::rescannable_from`. Returns `false` if a job for `source` was already
+/// recorded.
+pub fn record_processed_job(
+    source: LogSource,
+    mut job: ProcessedJob,
+    policy: &RetentionPolicy,
+    rescannable_from: &BTreeMap<u64, u64>,
+) -> bool {
+    if is_processed(&source) {
+        return false;
+    }
+    let now = job.processed_at;
+    job.sequence = PROCESSED_JOBS_INDEX.with(|index| {
+        index
+            .borrow()
+            .last_key_value()
+            .map_or(0, |(sequence, _)| sequence + 1)
+    });
+    PROCESSED_JOBS_INDEX.with(|index| index.borrow_mut().insert(job.sequence, source.clone()));
+    PROCESSED_JOBS.with(|jobs| jobs.borrow_mut().insert(source, job));
+    prune_processed_jobs(policy, rescannable_from, now);
+    true
+}
+
+pub fn get_processed_job(source: &LogSource) -> Option<ProcessedJob> {
+    PROCESSED_JOBS.with(|jobs| jobs.borrow().get(source))
+}
+
+pub fn is_processed(source: &LogSource) -> bool {
+    PROCESSED_JOBS.with(|jobs| jobs.borrow().contains_key(source))
+}
+
+pub fn remove_processed_job(source: &LogSource) -> Option<ProcessedJob> {
+    let job = PROCESSED_JOBS.with(|jobs| jobs.borrow_mut().remove(source))?;
+    PROCESSED_JOBS_INDEX.with(|index| index.borrow_mut().remove(&job.sequence));
+    Some(job)
+}
+
+/// Returns a page of processed jobs, oldest first.
+pub fn processed_jobs(offset: usize, limit: usize) -> Vec<(LogSource, ProcessedJob)> {
+    PROCESSED_JOBS_INDEX.with(|index| {
+        index
+            .borrow()
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .filter_map(|(_, source)| get_processed_job(&source).map(|job| (source, job)))
+            .collect()
+    })
+}
+
+/// Returns the processed jobs of `chain_id` whose log is in a block after
+/// `block_number`.
+pub fn processed_jobs_after_block(chain_id: u64, block_number: u64) -> Vec<LogSource> {
+    PROCESSED_JOBS.with(|jobs| {
+        jobs.borrow()
+            .iter()
+            .filter(|(_, job)| {
+                job.chain_id == chain_id && job.block_number.is_some_and(|n| n > block_number)
+            })
+            .map(|(source, _)| source)
+            .collect()
+    })
+}
+
+pub fn record_attestation(attestation: Attestation) {
+    ATTESTATIONS.with(|attestations| {
+        attestations
+            .borrow_mut()
+            .insert(attestation.key(), attestation)
+    });
+}
+
+pub fn get_attestation(key: &AttestationKey) -> Option<Attestation> {
+    ATTESTATIONS.with(|attestations| attestations.borrow().get(key))
+}
+
+pub fn remove_attestation(key: &AttestationKey) -> Option<Attestation> {
+    ATTESTATIONS.with(|attestations| attestations.borrow_mut().remove(key))
+}
+
+/// Removes the oldest processed jobs until the retention policy is satisfied. Jobs in
+/// blocks that a reorg can still make the scraper scan again are skipped, as pruning
+/// them would run them a second time when their log is scraped again. This only holds
+/// back the jobs of the chain that they are from.
+fn prune_processed_jobs(policy: &RetentionPolicy, rescannable_from: &BTreeMap<u64, u64>, now: u64) {
+    let mut len = PROCESSED_JOBS.with(|jobs| jobs.borrow().len());
+    let mut pruned = vec![];
+    PROCESSED_JOBS_INDEX.with(|index| {
+        for (sequence, source) in index.borrow().iter() {
+            let Some(job) = get_processed_job(&source) else {
+                pruned.push((sequence, source));
+                continue;
+            };
+            let too_many = policy.max_entries.is_some_and(|max| len > max);
+            let too_old = policy.max_age_secs.is_some_and(|max_age| {
+                now.saturating_sub(job.processed_at) > max_age.saturating_mul(NANOS_PER_SEC)
+            });
+            if !too_many && !too_old {
+                break;
+            }
+            let is_rescannable = job.block_number.is_some_and(|block_number| {
+                rescannable_from
+                    .get(&job.chain_id)
+                    .is_some_and(|from| block_number >= *from)
+            });
+            if !is_rescannable {
+                pruned.push((sequence, source));
+                len -= 1;
+            }
+        }
+    });
+    for (sequence, source) in pruned {
+        PROCESSED_JOBS_INDEX.with(|index| index.borrow_mut().remove(&sequence));
+        PROCESSED_JOBS.with(|jobs| jobs.borrow_mut().remove(&source));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Job, JobStatus};
+    use alloy::rpc::types::Log;
+
+    #[test]
+    fn should_reject_unreadable_state() {
+        assert!(decode_state(&[]).is_err());
+        assert!(decode_state(&[STATE_VERSION, 0xff]).is_err());
+        assert!(decode_state(&[STATE_VERSION + 1, 0xa0]).is_err());
+    }
+
+    fn processed_job(chain_id: u64, block_number: u64, processed_at: u64) -> ProcessedJob {
+        let log = Log {
+            block_number: Some(block_number),
+            ..Default::default()
+        };
+        ProcessedJob::new(
+            Job::new(chain_id, log),
+            JobStatus::Unhandled,
+            processed_at * NANOS_PER_SEC,
+        )
+    }
+
+    fn source(tx_hash: u8) -> LogSource {
+        LogSource {
+            transaction_hash: FixedBytes::repeat_byte(tx_hash),
+            log_index: 0,
+        }
+    }
+
+    fn processed_sources() -> Vec<LogSource> {
+        processed_jobs(0, usize::MAX)
+            .into_iter()
+            .map(|(source, _)| source)
+            .collect()
+    }
+
+    #[test]
+    fn should_prune_the_oldest_jobs_beyond_max_entries() {
+        let policy = RetentionPolicy {
+            max_entries: Some(2),
+            max_age_secs: None,
+        };
+        for tx_hash in 1..=4 {
+            let job = processed_job(1, tx_hash as u64, 0);
+            assert!(record_processed_job(
+                source(tx_hash),
+                job,
+                &policy,
+                &BTreeMap::new()
+            ));
+        }
+
+        assert_eq!(processed_sources(), vec![source(3), source(4)]);
+        assert!(!is_processed(&source(1)));
+    }
+
+    #[test]
+    fn should_prune_jobs_older_than_max_age() {
+        let policy = RetentionPolicy {
+            max_entries: None,
+            max_age_secs: Some(60),
+        };
+        record_processed_job(source(1), processed_job(1, 1, 0), &policy, &BTreeMap::new());
+        record_processed_job(
+            source(2),
+            processed_job(1, 2, 50),
+            &policy,
+            &BTreeMap::new(),
+        );
+        assert_eq!(processed_sources(), vec![source(1), source(2)]);
+
+        record_processed_job(
+            source(3),
+            processed_job(1, 3, 100),
+            &policy,
+            &BTreeMap::new(),
+        );
+
+        assert_eq!(processed_sources(), vec![source(2), source(3)]);
+    }
+
+    #[test]
+    fn should_keep_rescannable_jobs_and_prune_those_of_other_chains() {
+        let policy = RetentionPolicy {
+            max_entries: Some(2),
+            max_age_secs: None,
+        };
+        // chain 1 stalled at block 10, which a reorg can still make it scan again
+        let rescannable_from = BTreeMap::from([(1, 10), (2, 100)]);
+        record_processed_job(
+            source(1),
+            processed_job(1, 10, 0),
+            &policy,
+            &rescannable_from,
+        );
+        record_processed_job(
+            source(2),
+            processed_job(2, 1, 0),
+            &policy,
+            &rescannable_from,
+        );
+        record_processed_job(
+            source(3),
+            processed_job(2, 2, 0),
+            &policy,
+            &rescannable_from,
+        );
+        record_processed_job(
+            source(4),
+            processed_job(2, 3, 0),
+            &policy,
+            &rescannable_from,
+        );
+
+        assert_eq!(processed_sources(), vec![source(1), source(4)]);
+    }
+}
@@ -11,7 +11,30 @@ contract Coprocessor {
 
     mapping(uint => string) public jobs;
 
-    event NewJob(uint indexed job_id);
+    // The account that created each job. Attested results are bound to it, so
+    // that an attestation cannot be used for another job that got the same id
+    // after a reorg.
+    mapping(uint => address) public requesters;
+
+    // EIP-712 type hashes of the results that the coprocessor signs instead of
+    // submitting them itself, see `submitAttestedResult`
+    bytes32 private constant DOMAIN_TYPEHASH =
+        keccak256(
+            "EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)"
+        );
+    bytes32 private constant JOB_RESULT_TYPEHASH =
+        keccak256(
+            "JobResult(uint256 jobId,address requester,string result,uint256 deadline,uint256 chainId,address contractAddress)"
+        );
+    // Half the order of secp256k1. Signatures with a higher s are malleable.
+    uint256 private constant SECP256K1N_HALF =
+        0x7FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF5D576E7357A4501DDFE92F46681B20A0;
+
+    // `value` is what the job paid, which the coprocessor checks against its price
+    event NewJob(uint indexed job_id, address indexed requester, uint value);
+
+    // Emitted by `callbackBatch` for each result that could not be stored
+    event CallbackFailed(uint indexed job_id, string reason);
 
     // Function to create a new job
     function newJob() public payable {
@@ -23,8 +46,10 @@ contract Coprocessor {
         // contract.
         coprocessor.transfer(msg.value);
 
+        requesters[job_id] = msg.sender;
+
         // Emit the new job event
-        emit NewJob(job_id);
+        emit NewJob(job_id, msg.sender, msg.value);
 
         // Increment job counter
         job_id++;
@@ -42,6 +67,88 @@ contract Coprocessor {
         jobs[_job_id] = _result;
     }
 
+    // Stores the results of several jobs in one transaction. A result that
+    // cannot be stored does not revert the others, it is reported with a
+    // `CallbackFailed` event instead.
+    function callbackBatch(
+        string[] calldata _results,
+        uint256[] calldata _job_ids
+    ) public {
+        require(
+            msg.sender == coprocessor,
+            "Only the coprocessor can call this function"
+        );
+        require(
+            _results.length == _job_ids.length,
+            "The number of results and job ids differ"
+        );
+        for (uint i = 0; i < _job_ids.length; i++) {
+            if (_job_ids[i] >= job_id) {
+                emit CallbackFailed(_job_ids[i], "Unknown job");
+                continue;
+            }
+            jobs[_job_ids[i]] = _results[i];
+        }
+    }
+
+    // Stores a result that was signed by the coprocessor. Anyone can submit it
+    // and pays the gas, until the deadline of the signature has passed. The
+    // first result stored for a job is final.
+    function submitAttestedResult(
+        uint256 _job_id,
+        string calldata _result,
+        uint256 _deadline,
+        bytes calldata _signature
+    ) public {
+        require(_job_id < job_id, "Unknown job");
+        require(bytes(jobs[_job_id]).length == 0, "Result already submitted");
+        require(block.timestamp <= _deadline, "Attestation expired");
+        require(_signature.length == 65, "Invalid signature length");
+        bytes32 digest = attestationDigest(_job_id, _result, _deadline);
+        uint8 v = uint8(_signature[64]);
+        bytes32 s = bytes32(_signature[32:64]);
+        require(v == 27 || v == 28, "Invalid signature v value");
+        require(uint256(s) <= SECP256K1N_HALF, "Invalid signature s value");
+        address signer = ecrecover(digest, v, bytes32(_signature[0:32]), s);
+        require(
+            signer != address(0) && signer == coprocessor,
+            "Invalid signature"
+        );
+        jobs[_job_id] = _result;
+    }
+
+    // The EIP-712 hash of the `JobResult` that the coprocessor signs for a job.
+    function attestationDigest(
+        uint256 _job_id,
+        string calldata _result,
+        uint256 _deadline
+    ) internal view returns (bytes32) {
+        bytes32 domainSeparator = keccak256(
+            abi.encode(
+                DOMAIN_TYPEHASH,
+                keccak256("Coprocessor"),
+                keccak256("1"),
+                block.chainid,
+                address(this)
+            )
+        );
+        bytes32 structHash = keccak256(
+            abi.encode(
+                JOB_RESULT_TYPEHASH,
+                _job_id,
+                requesters[_job_id],
+                keccak256(bytes(_result)),
+                _deadline,
+                block.chainid,
+                address(this)
+            )
+        );
+        return
+            keccak256(
+                abi.encodePacked("\x19\x01", domainSeparator, structHash)
+            );
+    }
+
     function updateCoprocessor(address _coprocessor) public {
         require(
             msg.sender == coprocessor,
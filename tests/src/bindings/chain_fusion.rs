@@ -3,6 +3,52 @@
 #![allow(dead_code, unused_imports, non_snake_case)]
 use candid::{self, CandidType, Decode, Deserialize, Encode, Principal};
 
+#[derive(CandidType, Deserialize)]
+pub enum TaskType {
+    CheckGasBalances,
+    SubmitBatches,
+    ProcessLogs,
+    MonitorTransactions,
+    ScrapeLogs,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ActiveTask {
+    pub task: TaskType,
+    pub acquired_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Alert {
+    pub message: String,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AttestationInfo {
+    pub result: String,
+    pub signature: String,
+    pub requester: String,
+    pub chain_id: u64,
+    pub coprocessor_evm_address: String,
+    pub deadline: u64,
+    pub job_id: candid::Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AuditLogEntry {
+    pub change: String,
+    pub timestamp: u64,
+    pub caller: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum ConfirmationPolicy {
+    Safe,
+    Finalized,
+    Confirmations(u64),
+}
+
 #[derive(CandidType, Deserialize)]
 pub enum EcdsaCurve {
     #[serde(rename = "secp256k1")]
@@ -56,6 +102,135 @@ pub enum RpcService {
     Provider(u64),
 }
 
+#[derive(CandidType, Deserialize)]
+pub enum ReorgPolicy {
+    Rerun,
+    Compensate,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct BatchPolicy {
+    pub max_batch_size: u32,
+    pub window_secs: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PaymentPolicy {
+    pub margin_percent: u64,
+    pub gas_per_job: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ResubmissionPolicy {
+    pub timeout_secs: u64,
+    pub fee_bump_percent: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RetentionPolicy {
+    pub max_entries: Option<u64>,
+    pub max_age_secs: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RetryPolicy {
+    pub max_backoff_secs: u64,
+    pub initial_backoff_secs: u64,
+    pub max_attempts: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum EthSepoliaService {
+    Alchemy,
+    BlockPi,
+    PublicNode,
+    Ankr,
+    Sepolia,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum L2MainnetService1 {
+    Alchemy,
+    Llama,
+    BlockPi,
+    PublicNode,
+    Ankr,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum EthMainnetService1 {
+    Alchemy,
+    Llama,
+    BlockPi,
+    Cloudflare,
+    PublicNode,
+    Ankr,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum RpcServices {
+    EthSepolia(Option<Vec<EthSepoliaService>>),
+    BaseMainnet(Option<Vec<L2MainnetService1>>),
+    Custom {
+        #[serde(rename = "chainId")]
+        chain_id: u64,
+        services: Vec<RpcApi>,
+    },
+    OptimismMainnet(Option<Vec<L2MainnetService1>>),
+    ArbitrumOne(Option<Vec<L2MainnetService1>>),
+    EthMainnet(Option<Vec<EthMainnetService1>>),
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum ConsensusStrategy {
+    Equality,
+    Threshold { min: u8, total: Option<u8> },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EvmRpcScraping {
+    pub consensus: ConsensusStrategy,
+    pub rpc_services: RpcServices,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum ScrapingBackend {
+    Provider,
+    EvmRpc(EvmRpcScraping),
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum ScrapingCadence {
+    Fixed {
+        interval_secs: u64,
+    },
+    Adaptive {
+        min_interval_secs: u64,
+        max_interval_secs: u64,
+    },
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum ResultSink {
+    Attestation,
+    Transaction,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ChainArg {
+    pub rpc_service: RpcService,
+    pub filter_addresses: Vec<String>,
+    pub chain_id: u64,
+    pub coprocessor_evm_address: String,
+    pub filter_events: Vec<String>,
+    pub start_block: Option<u64>,
+    pub confirmation_policy: Option<ConfirmationPolicy>,
+    pub scraping_backend: Option<ScrapingBackend>,
+    pub scraping_cadence: Option<ScrapingCadence>,
+    pub result_chain_id: Option<u64>,
+    pub result_sink: Option<ResultSink>,
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct InitArg {
     pub ecdsa_key_id: EcdsaKeyId,
@@ -64,6 +239,161 @@ pub struct InitArg {
     pub chain_id: u64,
     pub coprocessor_evm_address: String,
     pub filter_events: Vec<String>,
+    pub start_block: Option<u64>,
+    pub confirmation_policy: Option<ConfirmationPolicy>,
+    pub reorg_policy: Option<ReorgPolicy>,
+    pub retry_policy: Option<RetryPolicy>,
+    pub retention_policy: Option<RetentionPolicy>,
+    pub resubmission_policy: Option<ResubmissionPolicy>,
+    pub scraping_backend: Option<ScrapingBackend>,
+    pub scraping_cadence: Option<ScrapingCadence>,
+    pub initial_scraping_delay_secs: Option<u64>,
+    pub max_jobs_in_flight: Option<u32>,
+    pub min_cycles_balance: Option<candid::Nat>,
+    pub payment_policy: Option<PaymentPolicy>,
+    pub batch_policy: Option<BatchPolicy>,
+    pub result_chain_id: Option<u64>,
+    pub result_sink: Option<ResultSink>,
+    pub chains: Option<Vec<ChainArg>>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct UpgradeArg {
+    pub rpc_service: Option<RpcService>,
+    pub filter_addresses: Option<Vec<String>>,
+    pub chain_id: Option<u64>,
+    pub coprocessor_evm_address: Option<String>,
+    pub filter_events: Option<Vec<String>>,
+    pub confirmation_policy: Option<ConfirmationPolicy>,
+    pub reorg_policy: Option<ReorgPolicy>,
+    pub retry_policy: Option<RetryPolicy>,
+    pub retention_policy: Option<RetentionPolicy>,
+    pub resubmission_policy: Option<ResubmissionPolicy>,
+    pub scraping_backend: Option<ScrapingBackend>,
+    pub scraping_cadence: Option<ScrapingCadence>,
+    pub initial_scraping_delay_secs: Option<u64>,
+    pub max_jobs_in_flight: Option<u32>,
+    pub min_cycles_balance: Option<candid::Nat>,
+    pub payment_policy: Option<PaymentPolicy>,
+    pub batch_policy: Option<BatchPolicy>,
+    pub result_chain_id: Option<u64>,
+    pub result_sink: Option<ResultSink>,
+    pub chains: Option<Vec<ChainArg>>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum CanisterArg {
+    Init(InitArg),
+    Upgrade(Option<UpgradeArg>),
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct LogSource {
+    pub transaction_hash: String,
+    pub log_index: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum JobStatus {
+    Failed { reason: String },
+    Reverted,
+    Confirmed { tx_hash: String },
+    ExecutionReverted { tx_hash: String, reason: String },
+    Computing,
+    Computed,
+    Unhandled,
+    Compensated,
+    Submitted { tx_hash: String },
+    DeadLetter,
+    Rejected {
+        paid: candid::Nat,
+        price: candid::Nat,
+    },
+    Attested,
+    Pending,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CyclesSpent {
+    pub rpc: candid::Nat,
+    pub scraping: candid::Nat,
+    pub signing: candid::Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct JobInfo {
+    pub status: JobStatus,
+    pub result_chain_id: Option<u64>,
+    pub result_tx_hash: Option<String>,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub log_source: LogSource,
+    pub chain_id: u64,
+    pub block_number: Option<u64>,
+    pub job_id: Option<candid::Nat>,
+    pub gas_used: Option<candid::Nat>,
+    pub cycles: CyclesSpent,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CyclesUsage {
+    pub balance: candid::Nat,
+    pub spent: CyclesSpent,
+    pub min_cycles_balance: Option<candid::Nat>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GasBalanceStatus {
+    pub callback_cost: Option<candid::Nat>,
+    pub checked_at: Option<u64>,
+    pub balance: Option<candid::Nat>,
+    pub chain_id: u64,
+    pub sufficient: bool,
+    pub waiting_jobs: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct InFlightTransactionInfo {
+    pub log_source: Option<LogSource>,
+    pub nonce: u64,
+    pub tx_hash: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct NonceStatus {
+    pub in_flight: Vec<InFlightTransactionInfo>,
+    pub next_nonce: Option<u64>,
+    pub chain_id: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum Result_ {
+    Ok,
+    Err(String),
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum Result1 {
+    Ok(Option<JobInfo>),
+    Err(String),
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum Result2 {
+    Ok(Vec<TaskType>),
+    Err(String),
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum Result3 {
+    Ok(Option<AttestationInfo>),
+    Err(String),
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum Result4 {
+    Ok(AttestationInfo),
+    Err(String),
 }
 
 pub struct ChainFusionCanister {
@@ -72,6 +402,87 @@ pub struct ChainFusionCanister {
 }
 
 impl ChainFusionCanister {
+    pub fn add_filter_address(&self, arg0: u64, arg1: String) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0, &arg1);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "add_filter_address",
+            args,
+        )
+    }
+    pub fn add_filter_event(&self, arg0: u64, arg1: String) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0, &arg1);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "add_filter_event",
+            args,
+        )
+    }
+    pub fn clear_stale_guards(&self, arg0: Option<u64>) -> super::CallBuilder<Result2> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "clear_stale_guards",
+            args,
+        )
+    }
+    pub fn get_active_tasks(&self) -> super::CallBuilder<Vec<ActiveTask>> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_active_tasks",
+            args,
+        )
+    }
+    pub fn get_alerts(&self) -> super::CallBuilder<Vec<Alert>> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_alerts",
+            args,
+        )
+    }
+    pub fn get_attestation(&self, arg0: u64, arg1: candid::Nat) -> super::CallBuilder<Result3> {
+        let args = Encode!(&arg0, &arg1);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_attestation",
+            args,
+        )
+    }
+    pub fn get_audit_log(&self) -> super::CallBuilder<Vec<AuditLogEntry>> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_audit_log",
+            args,
+        )
+    }
+    pub fn get_cycles_usage(&self) -> super::CallBuilder<CyclesUsage> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_cycles_usage",
+            args,
+        )
+    }
+    pub fn get_dead_letter_jobs(&self) -> super::CallBuilder<Vec<JobInfo>> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_dead_letter_jobs",
+            args,
+        )
+    }
     pub fn get_evm_address(&self) -> super::CallBuilder<Option<String>> {
         let args = Encode!();
         self.caller.call(
@@ -81,6 +492,208 @@ impl ChainFusionCanister {
             args,
         )
     }
+    pub fn get_gas_balances(&self) -> super::CallBuilder<Vec<GasBalanceStatus>> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_gas_balances",
+            args,
+        )
+    }
+    pub fn get_job(&self, arg0: LogSource) -> super::CallBuilder<Result1> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_job",
+            args,
+        )
+    }
+    pub fn get_nonce_status(&self) -> super::CallBuilder<Vec<NonceStatus>> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_nonce_status",
+            args,
+        )
+    }
+    pub fn get_pending_jobs(&self, arg0: u64, arg1: u64) -> super::CallBuilder<Vec<JobInfo>> {
+        let args = Encode!(&arg0, &arg1);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_pending_jobs",
+            args,
+        )
+    }
+    pub fn get_processed_jobs(&self, arg0: u64, arg1: u64) -> super::CallBuilder<Vec<JobInfo>> {
+        let args = Encode!(&arg0, &arg1);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_processed_jobs",
+            args,
+        )
+    }
+    pub fn pause_processing(&self) -> super::CallBuilder<Result_> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "pause_processing",
+            args,
+        )
+    }
+    pub fn pause_scraping(&self) -> super::CallBuilder<Result_> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "pause_scraping",
+            args,
+        )
+    }
+    pub fn remove_filter_address(&self, arg0: u64, arg1: String) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0, &arg1);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "remove_filter_address",
+            args,
+        )
+    }
+    pub fn remove_filter_event(&self, arg0: u64, arg1: String) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0, &arg1);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "remove_filter_event",
+            args,
+        )
+    }
+    pub fn renew_attestation(
+        &self,
+        arg0: u64,
+        arg1: candid::Nat,
+        arg2: Option<String>,
+    ) -> super::CallBuilder<Result4> {
+        let args = Encode!(&arg0, &arg1, &arg2);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "renew_attestation",
+            args,
+        )
+    }
+    pub fn resume_processing(&self) -> super::CallBuilder<Result_> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "resume_processing",
+            args,
+        )
+    }
+    pub fn resume_scraping(&self) -> super::CallBuilder<Result_> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "resume_scraping",
+            args,
+        )
+    }
+    pub fn retry_dead_letter_job(&self, arg0: LogSource) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "retry_dead_letter_job",
+            args,
+        )
+    }
+    pub fn set_batch_policy(&self, arg0: Option<BatchPolicy>) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "set_batch_policy",
+            args,
+        )
+    }
+    pub fn set_max_jobs_in_flight(&self, arg0: u32) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "set_max_jobs_in_flight",
+            args,
+        )
+    }
+    pub fn set_min_cycles_balance(&self, arg0: Option<candid::Nat>) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "set_min_cycles_balance",
+            args,
+        )
+    }
+    pub fn set_payment_policy(&self, arg0: Option<PaymentPolicy>) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "set_payment_policy",
+            args,
+        )
+    }
+    pub fn set_result_sink(&self, arg0: u64, arg1: ResultSink) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0, &arg1);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "set_result_sink",
+            args,
+        )
+    }
+    pub fn set_rpc_service(&self, arg0: u64, arg1: RpcService) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0, &arg1);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "set_rpc_service",
+            args,
+        )
+    }
+    pub fn set_scraping_backend(
+        &self,
+        arg0: u64,
+        arg1: ScrapingBackend,
+    ) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0, &arg1);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "set_scraping_backend",
+            args,
+        )
+    }
+    pub fn set_scraping_cadence(
+        &self,
+        arg0: u64,
+        arg1: ScrapingCadence,
+    ) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0, &arg1);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "set_scraping_cadence",
+            args,
+        )
+    }
 }
 pub const CANISTER_ID: Principal = Principal::from_slice(&[0, 0, 0, 0, 0, 160, 190, 169, 1, 1]); // 2222s-4iaaa-aaaaf-ax2uq-cai
 
@@ -93,7 +706,7 @@ pub fn new(caller: &super::Caller, canister_id: Principal) -> ChainFusionCaniste
 
 pub fn deploy(
     deployer: &super::Deployer,
-    arg0: InitArg,
+    arg0: CanisterArg,
 ) -> super::DeployBuilder<ChainFusionCanister> {
     let args = Encode!(&arg0);
     let result = deployer.deploy(args, new);
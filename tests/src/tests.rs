@@ -1,8 +1,8 @@
 use std::path::PathBuf;
 
 use alloy::{
-    hex::FromHex,
-    primitives::{utils::parse_ether, Address, Uint, U256},
+    hex::{self, FromHex},
+    primitives::{utils::parse_ether, Address, Bytes, Uint, U256},
 };
 use candid::Principal;
 use ic_test::{EvmUser, IcpTest, IcpUser};
@@ -21,7 +21,7 @@ struct Env {
     evm_user: EvmUser,
 }
 
-async fn setup(test: IcpTest) -> Env {
+async fn setup(test: IcpTest, batch_policy: Option<chain_fusion::BatchPolicy>) -> Env {
     let evm_user = test.evm.test_user(0);
     let icp_user = test.icp.test_user(0);
 
@@ -42,7 +42,7 @@ async fn setup(test: IcpTest) -> Env {
 
     let chain_fusion = chain_fusion::deploy(
         &icp_user,
-        chain_fusion::InitArg {
+        chain_fusion::CanisterArg::Init(chain_fusion::InitArg {
             ecdsa_key_id: chain_fusion::EcdsaKeyId {
                 curve: chain_fusion::EcdsaCurve::Secp256K1,
                 name: "dfx_test_key".to_string(),
@@ -54,8 +54,24 @@ async fn setup(test: IcpTest) -> Env {
             chain_id: test.evm.chain_id(),
             filter_addresses: vec![coprocessor.address().to_string()],
             coprocessor_evm_address: coprocessor.address().to_string(),
-            filter_events: vec!["NewJob(uint256)".to_string()],
-        },
+            filter_events: vec!["NewJob(uint256,address,uint256)".to_string()],
+            start_block: None,
+            confirmation_policy: None,
+            reorg_policy: None,
+            retry_policy: None,
+            retention_policy: None,
+            resubmission_policy: None,
+            scraping_backend: None,
+            scraping_cadence: None,
+            initial_scraping_delay_secs: None,
+            max_jobs_in_flight: None,
+            min_cycles_balance: None,
+            payment_policy: None,
+            batch_policy,
+            result_chain_id: None,
+            result_sink: None,
+            chains: None,
+        }),
     )
     .call()
     .await;
@@ -94,6 +110,27 @@ async fn setup(test: IcpTest) -> Env {
     }
 }
 
+/// Creates a job that pays 0.1 ETH.
+async fn new_job(coprocessor: &CoprocessorInstance<(), EvmUser>) {
+    let receipt = coprocessor
+        .newJob()
+        .value(parse_ether("0.1").unwrap())
+        .send()
+        .await
+        .unwrap()
+        .get_receipt()
+        .await
+        .unwrap();
+    assert!(receipt.status());
+}
+
+/// Gives the canister the time to scrape the logs and run the jobs.
+async fn run_jobs(test: &IcpTest) {
+    for _ in 0..100 {
+        test.icp.tick().await;
+    }
+}
+
 #[tokio::test]
 async fn test_coprocessor_job() {
     let Env {
@@ -102,15 +139,136 @@ async fn test_coprocessor_job() {
         evm_rpc,
         chain_fusion,
         coprocessor,
-    } = setup(IcpTest::new().await).await;
+    } = setup(IcpTest::new().await, None).await;
 
     let user_balance_before = test.evm.get_balance(evm_user.address).await;
 
     let payment = parse_ether("0.1").unwrap();
 
+    new_job(&coprocessor).await;
+
+    let user_balance_after = test.evm.get_balance(evm_user.address).await;
+
+    // This is not a strict equality because of gas cost payments.
+    assert!(user_balance_before - payment >= user_balance_after);
+
+    run_jobs(&test).await;
+
+    let result = coprocessor.getResult(Uint::from(0)).call().await.unwrap();
+    assert_eq!(result._0, "6765");
+
+    let processed_jobs = chain_fusion.get_processed_jobs(0, 10).call().await;
+    assert_eq!(processed_jobs.len(), 1);
+    assert!(matches!(
+        processed_jobs[0].status,
+        chain_fusion::JobStatus::Confirmed { .. }
+    ));
+    assert!(processed_jobs[0].gas_used.is_some());
+
+    let cycles_usage = chain_fusion.get_cycles_usage().call().await;
+    assert!(cycles_usage.spent.scraping > candid::Nat::from(0u32));
+
+    let gas_balances = chain_fusion.get_gas_balances().call().await;
+    assert_eq!(gas_balances.len(), 1);
+    assert!(gas_balances[0].sufficient);
+}
+
+#[tokio::test]
+async fn test_batched_jobs() {
+    let Env {
+        test,
+        chain_fusion,
+        coprocessor,
+        ..
+    } = setup(
+        IcpTest::new().await,
+        Some(chain_fusion::BatchPolicy {
+            max_batch_size: 2,
+            window_secs: 10,
+        }),
+    )
+    .await;
+
+    for _ in 0..2 {
+        new_job(&coprocessor).await;
+    }
+
+    run_jobs(&test).await;
+
+    for job_id in 0..2 {
+        let result = coprocessor
+            .getResult(Uint::from(job_id))
+            .call()
+            .await
+            .unwrap();
+        assert_eq!(result._0, "6765");
+    }
+
+    // both results are submitted in one transaction
+    let processed_jobs = chain_fusion.get_processed_jobs(0, 10).call().await;
+    assert_eq!(processed_jobs.len(), 2);
+    assert!(processed_jobs
+        .iter()
+        .all(|job| matches!(job.status, chain_fusion::JobStatus::Confirmed { .. })));
+    assert!(processed_jobs[0].result_tx_hash.is_some());
+    assert_eq!(
+        processed_jobs[0].result_tx_hash,
+        processed_jobs[1].result_tx_hash
+    );
+}
+
+#[tokio::test]
+async fn test_attested_job() {
+    let Env {
+        test,
+        evm_user,
+        chain_fusion,
+        coprocessor,
+        ..
+    } = setup(IcpTest::new().await, None).await;
+
+    let result = chain_fusion
+        .set_result_sink(test.evm.chain_id(), chain_fusion::ResultSink::Attestation)
+        .call()
+        .await;
+    assert!(matches!(result, chain_fusion::Result_::Ok));
+
+    new_job(&coprocessor).await;
+
+    run_jobs(&test).await;
+
+    // the result is signed but not sent
+    let processed_jobs = chain_fusion.get_processed_jobs(0, 10).call().await;
+    assert_eq!(processed_jobs.len(), 1);
+    assert!(matches!(
+        processed_jobs[0].status,
+        chain_fusion::JobStatus::Attested
+    ));
+    let result = coprocessor.getResult(Uint::from(0)).call().await.unwrap();
+    assert_eq!(result._0, "");
+
+    let chain_fusion::Result3::Ok(Some(attestation)) = chain_fusion
+        .get_attestation(test.evm.chain_id(), candid::Nat::from(0u32))
+        .call()
+        .await
+    else {
+        panic!("the job was not attested");
+    };
+    assert_eq!(attestation.result, "6765");
+    assert_eq!(
+        Address::from_hex(&attestation.requester).unwrap(),
+        evm_user.address
+    );
+
+    // anyone can relay the attestation
+    let signature = Bytes::from(hex::decode(attestation.signature).unwrap());
     let receipt = coprocessor
-        .newJob()
-        .value(payment)
+        .submitAttestedResult(
+            U256::from(0),
+            attestation.result.clone(),
+            U256::from(attestation.deadline),
+            signature.clone(),
+        )
         .send()
         .await
         .unwrap()
@@ -119,15 +277,70 @@ async fn test_coprocessor_job() {
         .unwrap();
     assert!(receipt.status());
 
-    let user_balance_after = test.evm.get_balance(evm_user.address).await;
+    let result = coprocessor.getResult(Uint::from(0)).call().await.unwrap();
+    assert_eq!(result._0, "6765");
 
-    // This is not a strict equality because of gas cost payments.
-    assert!(user_balance_before - payment >= user_balance_after);
+    // the first result of a job is final
+    assert!(coprocessor
+        .submitAttestedResult(
+            U256::from(0),
+            attestation.result,
+            U256::from(attestation.deadline),
+            signature,
+        )
+        .send()
+        .await
+        .is_err());
 
-    for _ in 0..100 {
-        test.icp.tick().await;
+    // the attestation has not expired yet
+    let renewed = chain_fusion
+        .renew_attestation(test.evm.chain_id(), candid::Nat::from(0u32), None)
+        .call()
+        .await;
+    assert!(matches!(renewed, chain_fusion::Result4::Err(_)));
+}
+
+#[tokio::test]
+async fn test_upgrade_keeps_state() {
+    let Env {
+        test,
+        chain_fusion,
+        coprocessor,
+        ..
+    } = setup(IcpTest::new().await, None).await;
+
+    let evm_address = chain_fusion.get_evm_address().call().await;
+
+    for _ in 0..2 {
+        new_job(&coprocessor).await;
+
+        run_jobs(&test).await;
+
+        // the processed jobs and the configuration survive the upgrade, so the
+        // second job is run once and the first one is not run again
+        chain_fusion::deploy(
+            &test.icp.test_user(0),
+            chain_fusion::CanisterArg::Upgrade(None),
+        )
+        .with_upgrade()
+        .call()
+        .await;
     }
 
-    let result = coprocessor.getResult(Uint::from(0)).call().await.unwrap();
-    assert_eq!(result._0, "6765");
+    run_jobs(&test).await;
+
+    assert_eq!(chain_fusion.get_evm_address().call().await, evm_address);
+    let processed_jobs = chain_fusion.get_processed_jobs(0, 10).call().await;
+    assert_eq!(processed_jobs.len(), 2);
+    assert!(processed_jobs
+        .iter()
+        .all(|job| matches!(job.status, chain_fusion::JobStatus::Confirmed { .. })));
+    for job_id in 0..2 {
+        let result = coprocessor
+            .getResult(Uint::from(job_id))
+            .call()
+            .await
+            .unwrap();
+        assert_eq!(result._0, "6765");
+    }
 }